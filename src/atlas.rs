@@ -0,0 +1,238 @@
+use crate::game_assets::GameAssets;
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    utils::BoxedFuture,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Every sprite of the game packed in a single image
+pub const ATLAS: &str = "sprites/sprites.png";
+/// Where each sprite is in [`ATLAS`]
+pub const ATLAS_MANIFEST: &str = "sprites/sprites.atlas.ron";
+
+pub struct AtlasPlugin;
+
+impl Plugin for AtlasPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<AtlasManifest>()
+            .register_asset_loader(AtlasManifestLoader);
+    }
+}
+
+/// Written by `--pack-atlas` along with the atlas itself
+#[derive(Asset, TypePath, Serialize, Deserialize, PartialEq)]
+pub struct AtlasManifest {
+    /// Size of the atlas in pixels
+    size: Vec2,
+    /// Area of the atlas holding each sprite, in pixels. Sorted so packing
+    /// the same sprites writes the same manifest.
+    regions: BTreeMap<GameAssets, Rect>,
+}
+
+impl AtlasManifest {
+    /// Every sprite covers the whole atlas of the given size, for atlases
+    /// whose manifest is broken
+    pub fn new_placeholder(size: Vec2) -> Self {
+        let region = Rect::from_corners(Vec2::ZERO, size);
+        let regions = GameAssets::iterator()
+            .map(|&asset| (asset, region))
+            .collect();
+        Self { size, regions }
+    }
+
+    pub fn get_region(&self, asset: GameAssets) -> Option<Rect> {
+        self.regions.get(&asset).copied()
+    }
+
+    /// Area holding the sprite in texture coordinates, from 0 to 1
+    pub fn get_uv_rect(&self, asset: GameAssets) -> Option<Rect> {
+        let region = self.get_region(asset)?;
+        Some(Rect::from_corners(
+            region.min / self.size,
+            region.max / self.size,
+        ))
+    }
+}
+
+struct AtlasManifestLoader;
+
+#[derive(Debug)]
+pub enum AtlasManifestLoaderError {
+    Io(std::io::Error),
+    Ron(ron::error::SpannedError),
+}
+
+impl std::fmt::Display for AtlasManifestLoaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "Unable to read the atlas manifest: {err}"),
+            Self::Ron(err) => write!(f, "Invalid atlas manifest: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for AtlasManifestLoaderError {}
+
+impl AssetLoader for AtlasManifestLoader {
+    type Asset = AtlasManifest;
+    type Settings = ();
+    type Error = AtlasManifestLoaderError;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<AtlasManifest, AtlasManifestLoaderError>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader
+                .read_to_end(&mut bytes)
+                .await
+                .map_err(AtlasManifestLoaderError::Io)?;
+            ron::de::from_bytes(&bytes).map_err(AtlasManifestLoaderError::Ron)
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["atlas.ron"]
+    }
+}
+
+/// Whether `--pack-atlas` was given
+#[cfg(not(target_family = "wasm"))]
+pub fn is_pack_requested() -> bool {
+    std::env::args().skip(1).any(|arg| arg == "--pack-atlas")
+}
+
+/// Packs the sprites into [`ATLAS`] and writes the [`ATLAS_MANIFEST`] that
+/// goes with it, both are committed along with the sprites
+#[cfg(not(target_family = "wasm"))]
+pub fn pack() {
+    let folder = std::path::Path::new("assets");
+    let (atlas, manifest) = match pack_sprites(folder) {
+        Ok(packed) => packed,
+        Err(err) => {
+            eprintln!("{err}");
+            return;
+        }
+    };
+    if let Err(err) = atlas.save(folder.join(ATLAS)) {
+        eprintln!("Unable to write the atlas: {err}");
+        return;
+    }
+
+    let pretty = ron::ser::PrettyConfig::default();
+    let written = ron::ser::to_string_pretty(&manifest, pretty)
+        .map_err(|err| err.to_string())
+        .and_then(|data| {
+            std::fs::write(folder.join(ATLAS_MANIFEST), data).map_err(|err| err.to_string())
+        });
+    match written {
+        Ok(()) => println!(
+            "Packed {} sprites in a {}x{} atlas",
+            manifest.regions.len(),
+            atlas.width(),
+            atlas.height()
+        ),
+        Err(err) => eprintln!("Unable to write the atlas manifest: {err}"),
+    }
+}
+
+/// Lays out the sprite of each [`GameAssets`] in rows, the tallest first
+#[cfg(not(target_family = "wasm"))]
+fn pack_sprites(folder: &std::path::Path) -> Result<(image::RgbaImage, AtlasManifest), String> {
+    use crate::ATLAS_PADDING;
+    use image::{imageops, RgbaImage};
+
+    let mut sprites = Vec::new();
+    for asset in GameAssets::iterator() {
+        sprites.push((*asset, read_sprite(folder, asset.get_files())?));
+    }
+    sprites.sort_by_key(|(_, sprite)| std::cmp::Reverse(sprite.height()));
+
+    let area: u32 = sprites
+        .iter()
+        .map(|(_, sprite)| (sprite.width() + ATLAS_PADDING) * (sprite.height() + ATLAS_PADDING))
+        .sum();
+    let widest = sprites.iter().map(|(_, sprite)| sprite.width()).max();
+    let width = f64::from(area)
+        .sqrt()
+        .max(f64::from(widest.unwrap_or_default()))
+        .ceil() as u32;
+    let width = width.next_power_of_two();
+
+    let mut regions = BTreeMap::new();
+    let (mut x, mut y, mut row_height) = (0, 0, 0);
+    for (asset, sprite) in &sprites {
+        if x + sprite.width() > width {
+            (x, y, row_height) = (0, y + row_height + ATLAS_PADDING, 0);
+        }
+        let min = Vec2::new(x as f32, y as f32);
+        let size = Vec2::new(sprite.width() as f32, sprite.height() as f32);
+        regions.insert(*asset, Rect::from_corners(min, min + size));
+        x += sprite.width() + ATLAS_PADDING;
+        row_height = row_height.max(sprite.height());
+    }
+    let height = (y + row_height).next_power_of_two();
+
+    let mut atlas = RgbaImage::new(width, height);
+    for (asset, sprite) in &sprites {
+        let min = regions[asset].min;
+        imageops::replace(
+            &mut atlas,
+            sprite,
+            i64::from(min.x as u32),
+            i64::from(min.y as u32),
+        );
+    }
+    let manifest = AtlasManifest {
+        size: Vec2::new(width as f32, height as f32),
+        regions,
+    };
+    Ok((atlas, manifest))
+}
+
+/// Reads the given sprites and puts them next to each other
+#[cfg(not(target_family = "wasm"))]
+fn read_sprite(folder: &std::path::Path, files: &[&str]) -> Result<image::RgbaImage, String> {
+    use image::{imageops, RgbaImage};
+
+    let mut parts = Vec::new();
+    for file in files {
+        let path = folder.join(file);
+        let part = image::open(&path)
+            .map_err(|err| format!("Unable to read {}: {err}", path.display()))?;
+        parts.push(part.to_rgba8());
+    }
+    let width = parts.iter().map(RgbaImage::width).sum();
+    let height = parts.iter().map(RgbaImage::height).max();
+    let mut sprite = RgbaImage::new(width, height.unwrap_or_default());
+    let mut x = 0;
+    for part in &parts {
+        imageops::replace(&mut sprite, part, i64::from(x), 0);
+        x += part.width();
+    }
+    Ok(sprite)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn committed_atlas_matches_the_sprites() {
+        let folder = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets");
+        let (atlas, manifest) = pack_sprites(&folder).unwrap();
+
+        let committed_atlas = image::open(folder.join(ATLAS)).unwrap().to_rgba8();
+        let committed_manifest: AtlasManifest =
+            ron::de::from_bytes(&std::fs::read(folder.join(ATLAS_MANIFEST)).unwrap()).unwrap();
+        let message = "The atlas is out of date, run the game with --pack-atlas";
+        assert!(atlas == committed_atlas, "{message}");
+        assert!(manifest == committed_manifest, "{message}");
+    }
+}
@@ -0,0 +1,322 @@
+use crate::{
+    board::{Board, CellType},
+    enemies::{EnemyKilledEvent, EnemyMode},
+    pickup::{Pickup, PickupEvent, PickupKind},
+    simulation::{FinalTickEvents, GameTick, TickSet},
+    state::{GameEvent, GameGlobals, GameState},
+    storage, MAX_SIREN_SPEED,
+};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+const AUDIO_STORAGE: &str = "audio";
+
+/// Turns what happens in the game into sounds. The sounds are only requested
+/// here, the backend decides what to do with them.
+pub struct GameAudioPlugin;
+
+impl Plugin for GameAudioPlugin {
+    fn build(&self, app: &mut App) {
+        let settings = storage::load(AUDIO_STORAGE).unwrap_or_default();
+        app.insert_resource::<AudioSettings>(settings)
+            .add_event::<PlaySoundEvent>()
+            .init_resource::<LoopingSound>()
+            .add_systems(
+                GameTick,
+                queue_tick_sounds
+                    .after(TickSet::Collision)
+                    .run_if(in_state(GameState::Playing)),
+            )
+            .add_systems(
+                Update,
+                (
+                    queue_game_sounds.run_if(on_event::<GameEvent>()),
+                    update_looping_sound,
+                    save_audio_settings.run_if(
+                        resource_changed::<AudioSettings>
+                            .and_then(not(resource_added::<AudioSettings>)),
+                    ),
+                ),
+            );
+
+        #[cfg(feature = "audio")]
+        app.add_plugins(device::DeviceAudioPlugin);
+        #[cfg(not(feature = "audio"))]
+        app.add_systems(Update, discard_sounds.run_if(on_event::<PlaySoundEvent>()));
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Sound {
+    Waka,
+    EnemyEaten,
+    Death,
+    ExtraLife,
+    Intro,
+    /// Loops while the board is played, faster as the pickups run out
+    Siren,
+    /// Loops while the enemies are frightened
+    PowerPillSiren,
+}
+
+#[derive(Resource, Serialize, Deserialize)]
+pub struct AudioSettings {
+    /// From 0 to 1
+    pub volume: f32,
+    pub muted: bool,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        Self {
+            volume: 1.,
+            muted: false,
+        }
+    }
+}
+
+/// Plays a sound once
+#[derive(Event)]
+pub struct PlaySoundEvent(pub Sound);
+
+/// Sound that loops in the background, if any
+#[derive(Resource, Default, PartialEq)]
+pub struct LoopingSound {
+    pub sound: Option<Sound>,
+    pub speed: f32,
+}
+
+fn queue_tick_sounds(
+    mut pickups: FinalTickEvents<PickupEvent>,
+    mut enemies_killed: FinalTickEvents<EnemyKilledEvent>,
+    mut sound_writer: EventWriter<PlaySoundEvent>,
+    game_globals: Res<GameGlobals>,
+) {
+    let dot_eaten = pickups
+        .read()
+        .iter()
+        .any(|pickup| pickup.kind == PickupKind::Dot);
+    let enemy_eaten = !enemies_killed.read().is_empty();
+    if game_globals.attract_mode {
+        return;
+    }
+    if dot_eaten {
+        sound_writer.send(PlaySoundEvent(Sound::Waka));
+    }
+    if enemy_eaten {
+        sound_writer.send(PlaySoundEvent(Sound::EnemyEaten));
+    }
+}
+
+fn queue_game_sounds(
+    mut event_reader: EventReader<GameEvent>,
+    mut sound_writer: EventWriter<PlaySoundEvent>,
+    game_globals: Res<GameGlobals>,
+) {
+    let mut sounds = Vec::new();
+    for event in event_reader.read() {
+        let sound = match event {
+            GameEvent::NewGame {
+                attract_mode: false,
+                ..
+            } => Sound::Intro,
+            GameEvent::PlayerDies { .. } if !game_globals.attract_mode => Sound::Death,
+            GameEvent::ExtraLife { .. } if !game_globals.attract_mode => Sound::ExtraLife,
+            _ => continue,
+        };
+        // Players caught together only get one jingle
+        if !sounds.contains(&sound) {
+            sounds.push(sound);
+        }
+    }
+    for sound in sounds {
+        sound_writer.send(PlaySoundEvent(sound));
+    }
+}
+
+fn update_looping_sound(
+    mut looping: ResMut<LoopingSound>,
+    state: Res<State<GameState>>,
+    game_globals: Res<GameGlobals>,
+    enemies: Query<&EnemyMode>,
+    pickups: Query<(), With<Pickup>>,
+    board: Res<Board>,
+) {
+    let next = if *state.get() != GameState::Playing || game_globals.attract_mode {
+        LoopingSound::default()
+    } else if enemies.iter().any(|mode| *mode == EnemyMode::Frightened) {
+        LoopingSound {
+            sound: Some(Sound::PowerPillSiren),
+            speed: 1.,
+        }
+    } else {
+        let total = board.count_cells(CellType::Dot) + board.count_cells(CellType::PowerPill);
+        let eaten = 1. - pickups.iter().count() as f32 / total.max(1) as f32;
+        LoopingSound {
+            sound: Some(Sound::Siren),
+            speed: eaten.mul_add(MAX_SIREN_SPEED - 1., 1.),
+        }
+    };
+    looping.set_if_neq(next);
+}
+
+#[cfg(not(feature = "audio"))]
+fn discard_sounds(mut sound_reader: EventReader<PlaySoundEvent>) {
+    for PlaySoundEvent(sound) in sound_reader.read() {
+        debug!("Sound {sound:?} not played, audio is disabled");
+    }
+}
+
+fn save_audio_settings(settings: Res<AudioSettings>) {
+    storage::save(AUDIO_STORAGE, &*settings);
+}
+
+/// Plays the sounds on the audio device
+#[cfg(feature = "audio")]
+mod device {
+    use super::{AudioSettings, LoopingSound, PlaySoundEvent, Sound};
+    use crate::game_assets::LoadingQueue;
+    use bevy::{audio::Volume, prelude::*, utils::HashMap};
+
+    const WAKA: &str = "sounds/waka.ogg";
+    const ENEMY_EATEN: &str = "sounds/enemy-eaten.ogg";
+    const DEATH: &str = "sounds/death.ogg";
+    const EXTRA_LIFE: &str = "sounds/extra-life.ogg";
+    const INTRO: &str = "sounds/intro.ogg";
+    const SIREN: &str = "sounds/siren.ogg";
+    const POWER_PILL_SIREN: &str = "sounds/power-pill-siren.ogg";
+
+    pub struct DeviceAudioPlugin;
+
+    impl Plugin for DeviceAudioPlugin {
+        fn build(&self, app: &mut App) {
+            app.add_systems(PreStartup, load_sounds).add_systems(
+                Update,
+                (
+                    play_sounds.run_if(on_event::<PlaySoundEvent>()),
+                    play_looping_sound.run_if(
+                        resource_changed::<LoopingSound>.or_else(resource_changed::<AudioSettings>),
+                    ),
+                ),
+            );
+        }
+    }
+
+    impl Sound {
+        const fn get_file(self) -> &'static str {
+            match self {
+                Self::Waka => WAKA,
+                Self::EnemyEaten => ENEMY_EATEN,
+                Self::Death => DEATH,
+                Self::ExtraLife => EXTRA_LIFE,
+                Self::Intro => INTRO,
+                Self::Siren => SIREN,
+                Self::PowerPillSiren => POWER_PILL_SIREN,
+            }
+        }
+    }
+
+    impl AudioSettings {
+        /// Volume the sounds are played at
+        const fn get_volume(&self) -> f32 {
+            if self.muted {
+                0.
+            } else {
+                self.volume
+            }
+        }
+    }
+
+    #[derive(Resource)]
+    struct SoundHandles(HashMap<Sound, Handle<AudioSource>>);
+
+    /// The entity playing the [`LoopingSound`]
+    #[derive(Component)]
+    struct LoopingSink(Sound);
+
+    fn load_sounds(
+        mut commands: Commands,
+        mut loading_queue: ResMut<LoadingQueue>,
+        asset_server: Res<AssetServer>,
+    ) {
+        let sounds = [
+            Sound::Waka,
+            Sound::EnemyEaten,
+            Sound::Death,
+            Sound::ExtraLife,
+            Sound::Intro,
+            Sound::Siren,
+            Sound::PowerPillSiren,
+        ];
+        let handles = sounds
+            .into_iter()
+            .map(|sound| {
+                let handle: Handle<AudioSource> = asset_server.load(sound.get_file());
+                loading_queue.add(handle.clone());
+                (sound, handle)
+            })
+            .collect();
+        commands.insert_resource(SoundHandles(handles));
+    }
+
+    fn play_sounds(
+        mut commands: Commands,
+        mut sound_reader: EventReader<PlaySoundEvent>,
+        handles: Res<SoundHandles>,
+        settings: Res<AudioSettings>,
+    ) {
+        for PlaySoundEvent(sound) in sound_reader.read() {
+            if settings.muted {
+                continue;
+            }
+            commands.spawn(AudioBundle {
+                source: handles.0[sound].clone(),
+                settings: PlaybackSettings::DESPAWN.with_volume(Volume::new(settings.volume)),
+            });
+        }
+    }
+
+    /// Speed and volume changes are applied to the sound being played,
+    /// a different sound starts over. Sounds that did not start yet take
+    /// them when they do.
+    fn play_looping_sound(
+        mut commands: Commands,
+        looping: Res<LoopingSound>,
+        settings: Res<AudioSettings>,
+        handles: Res<SoundHandles>,
+        mut query: Query<(
+            Entity,
+            &LoopingSink,
+            Option<&AudioSink>,
+            &mut PlaybackSettings,
+        )>,
+    ) {
+        let mut playing = false;
+        for (entity, sink, audio_sink, mut playback) in &mut query {
+            if looping.sound != Some(sink.0) {
+                commands.entity(entity).despawn();
+                continue;
+            }
+            if let Some(audio_sink) = audio_sink {
+                audio_sink.set_speed(looping.speed);
+                audio_sink.set_volume(settings.get_volume());
+            } else {
+                playback.speed = looping.speed;
+                playback.volume = Volume::new(settings.get_volume());
+            }
+            playing = true;
+        }
+        let Some(sound) = looping.sound.filter(|_| !playing) else {
+            return;
+        };
+        commands.spawn((
+            AudioBundle {
+                source: handles.0[&sound].clone(),
+                settings: PlaybackSettings::LOOP
+                    .with_volume(Volume::new(settings.get_volume()))
+                    .with_speed(looping.speed),
+            },
+            LoopingSink(sound),
+        ));
+    }
+}
@@ -1,22 +1,56 @@
 use crate::{
+    atlas::AtlasManifest,
     collision::Collider,
     enemies::{Enemy, EnemyAI},
-    game_assets::{GameAssets, GameAssetsLoader},
+    game_assets::{GameAnimations, GameAssets, GameAssetsLoader},
     movement::{Directions, Position},
     pickup::{Dot, Pickup, PowerPill},
-    CELL_SIZE, DOT_SCORE, PICKUP_RANGE, POWERPILL_SCORE,
+    simulation::{GameTick, Rollback, RollbackApp, SimulationTick, TickSet},
+    spritesheet::{AnimationState, SpriteSheetAnimator},
+    state::{GameGlobals, GameState, NewLevelEvent},
+    theme::{MazeColors, Palette},
+    tilemap::{self, Tile, TileChunk},
+    DOT_SCORE, PICKUP_RANGE, POWERPILL_SCORE,
 };
-use bevy::prelude::*;
+use bevy::{prelude::*, sprite::Mesh2dHandle};
 
 const DEFAULT_LAYOUT: &str = include_str!("default_layout.txt");
+/// The maze is drawn under everything moving on it
+const MAZE_DEPTH: f32 = -1.;
 
 pub struct BoardPlugin;
 
 impl Plugin for BoardPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
-        let board: Board = DEFAULT_LAYOUT.into();
-        app.add_systems(Startup, spawn_board_components)
-            .insert_resource(board);
+        app.init_resource::<Board>()
+            .init_resource::<MazePaint>()
+            .rollback_component::<Pickup>()
+            .add_systems(
+                Update,
+                (
+                    reload_maze.run_if(
+                        resource_changed::<GameAssetsLoader>
+                            .and_then(not(resource_added::<GameAssetsLoader>)),
+                    ),
+                    choose_maze_colors,
+                    spawn_maze.run_if(not(resource_exists::<MazeSpawned>)),
+                    repaint_maze.run_if(
+                        resource_changed::<MazePaint>.and_then(resource_exists::<MazeSpawned>),
+                    ),
+                )
+                    .chain(),
+            )
+            .add_systems(OnEnter(GameState::Dying), save_pickups)
+            .add_systems(OnEnter(GameState::LevelComplete), start_maze_flash)
+            .add_systems(OnExit(GameState::LevelComplete), stop_maze_flash)
+            .add_systems(Update, spawn_pickups.run_if(on_event::<NewLevelEvent>()))
+            .add_systems(PostUpdate, update_pickup_chunks)
+            .add_systems(
+                GameTick,
+                check_level_complete
+                    .in_set(TickSet::Outcome)
+                    .run_if(in_state(GameState::Playing)),
+            );
     }
 }
 
@@ -26,6 +60,9 @@ pub struct Board {
     rows: isize,
     cells: Vec<CellType>,
     enemies: Vec<Enemy>,
+    /// Cells joined to the pickups without going through a wall or a door,
+    /// the only ones players walk on
+    reachable: Vec<bool>,
 }
 
 #[derive(Component, Clone, Copy, PartialEq, Eq)]
@@ -37,6 +74,31 @@ pub enum CellType {
     Outside,
 }
 
+/// Present once the chunks of the maze are on the board, or once the atlas
+/// turned out to have no maze
+#[derive(Resource)]
+struct MazeSpawned;
+
+/// Colours the maze is drawn with, those of the level or the flash
+#[derive(Resource, PartialEq)]
+struct MazePaint(MazeColors);
+
+impl Default for MazePaint {
+    fn default() -> Self {
+        Self(Palette::default().get_maze_colors(1))
+    }
+}
+
+#[derive(Component, Clone)]
+struct WallChunk;
+
+#[derive(Component, Clone)]
+struct PickupChunk;
+
+/// Keeps the time of the walls flashing once the board is cleared
+#[derive(Component)]
+struct MazeFlash;
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum WallType {
     Vertical,
@@ -48,55 +110,258 @@ pub enum WallType {
     Door,
 }
 
-fn spawn_board_components(
+/// Walls never change, the pickups are drawn by a layer of their own. Waits
+/// for the atlas manifest to know where the tiles are.
+#[allow(clippy::too_many_arguments)]
+fn spawn_maze(
     mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
     game_assets_loader: Res<GameAssetsLoader>,
+    manifests: Res<Assets<AtlasManifest>>,
     board: Res<Board>,
+    paint: Res<MazePaint>,
+    pickups: Query<(&Position, Has<PowerPill>), With<Pickup>>,
 ) {
-    for (index, cell_type) in board.cells.iter().enumerate() {
-        let Some(asset) = cell_type.get_asset() else {
-            continue;
-        };
-        let row = index as isize / board.columns;
-        let column = index as isize % board.columns;
+    let Some(manifest) = game_assets_loader.get_manifest(&manifests) else {
+        return;
+    };
+    let Some(uv) = manifest.get_uv_rect(GameAssets::Maze) else {
+        // Only drawn again once a theme replaces the atlas
+        error!("The maze is missing from the atlas manifest");
+        commands.insert_resource(MazeSpawned);
+        return;
+    };
+    // The tiles carry their own colour
+    let material = materials.add(ColorMaterial::from(game_assets_loader.get_atlas()));
 
-        let position = Position::new(column as f32, row as f32);
-        let transform = position.get_transform();
+    // Pickups put on the board before the maze was drawn
+    let pickup_tiles: Vec<_> = pickups
+        .iter()
+        .map(|(position, power_pill)| (position.clone(), get_pickup_tile(power_pill)))
+        .collect();
+    tilemap::spawn_chunks(
+        &mut commands,
+        &mut meshes,
+        &material,
+        uv,
+        board.get_dimensions(),
+        MAZE_DEPTH,
+        &board.get_wall_tiles(),
+        &paint.0,
+        WallChunk,
+    );
+    tilemap::spawn_chunks(
+        &mut commands,
+        &mut meshes,
+        &material,
+        uv,
+        board.get_dimensions(),
+        MAZE_DEPTH,
+        &pickup_tiles,
+        &paint.0,
+        PickupChunk,
+    );
+    commands.insert_resource(MazeSpawned);
+}
 
-        let sprite_bundle = SpriteBundle {
-            texture: game_assets_loader.get(asset),
-            sprite: Sprite {
-                anchor: bevy::sprite::Anchor::Center,
-                rect: Some(Rect::new(0., 0., CELL_SIZE, CELL_SIZE)),
-                custom_size: Some(Vec2::new(CELL_SIZE, CELL_SIZE)),
-                ..Default::default()
-            },
-            transform,
-            ..Default::default()
-        };
+/// Throws the maze away when a theme replaces the atlas, it is drawn again
+/// once the new manifest is loaded
+#[allow(clippy::type_complexity)]
+fn reload_maze(
+    mut commands: Commands,
+    query: Query<Entity, Or<(With<WallChunk>, With<PickupChunk>)>>,
+) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    commands.remove_resource::<MazeSpawned>();
+}
+
+/// The colours of the level of the current player, swapped for the flash
+/// ones on the odd frames of the maze flash
+fn choose_maze_colors(
+    mut paint: ResMut<MazePaint>,
+    flash_query: Query<&SpriteSheetAnimator, With<MazeFlash>>,
+    palette: Res<Palette>,
+    game_globals: Res<GameGlobals>,
+) {
+    let flashing = flash_query
+        .get_single()
+        .is_ok_and(|animator| !animator.frame.is_multiple_of(2));
+    let colors = if flashing {
+        palette.flash
+    } else {
+        palette.get_maze_colors(game_globals.get_player().level)
+    };
+    paint.set_if_neq(MazePaint(colors));
+}
+
+/// Draws every chunk again with the new colours
+fn repaint_maze(
+    mut chunks: Query<(&TileChunk, &Mesh2dHandle, &mut Visibility, Has<WallChunk>)>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    pickups: Query<(&Position, Has<PowerPill>), With<Pickup>>,
+    board: Res<Board>,
+    paint: Res<MazePaint>,
+) {
+    let wall_tiles = board.get_wall_tiles();
+    let pickup_tiles: Vec<_> = pickups
+        .iter()
+        .map(|(position, power_pill)| (position.clone(), get_pickup_tile(power_pill)))
+        .collect();
+    for (chunk, mesh, visibility, is_wall) in &mut chunks {
+        let tiles = if is_wall { &wall_tiles } else { &pickup_tiles };
+        let tiles = tiles.iter().map(|(position, tile)| (position, *tile));
+        tilemap::fill_chunk(chunk, mesh, visibility, &mut meshes, tiles, &paint.0);
+    }
+}
 
+/// Pickups are only left off the pickups layer once eaten, this matches them
+/// all
+type BoardPickup = Or<(With<Dot>, With<PowerPill>)>;
+
+/// Fills the board with the pickups of the current player, removing whatever
+/// was left by the previous level or the previous player
+fn spawn_pickups(
+    mut commands: Commands,
+    board: Res<Board>,
+    game_globals: Res<GameGlobals>,
+    query: Query<Entity, BoardPickup>,
+    mut event_reader: EventReader<NewLevelEvent>,
+) {
+    event_reader.clear();
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    let remaining = game_globals.get_player().pickups.as_ref();
+    for (index, cell_type) in board.cells.iter().enumerate() {
+        let position = board.get_position(index);
+        if remaining.is_some_and(|pickups| !pickups.contains(&position)) {
+            continue;
+        }
+        let transform = position.get_transform();
         match cell_type {
-            CellType::Wall(_) => commands.spawn((position, sprite_bundle)),
             CellType::Dot => commands.spawn((
-                sprite_bundle,
                 Pickup::new(DOT_SCORE),
                 position,
+                transform,
                 Collider::new(PICKUP_RANGE),
                 Dot,
+                Rollback,
             )),
             CellType::PowerPill => commands.spawn((
-                sprite_bundle,
                 Pickup::new(POWERPILL_SCORE),
                 position,
+                transform,
                 Collider::new(PICKUP_RANGE),
                 PowerPill,
+                Rollback,
             )),
             _ => continue,
         };
     }
 }
 
+/// Draws the pickups again, only in the chunks where one was put on the
+/// board, eaten or brought back by a rollback
+#[allow(clippy::type_complexity)]
+fn update_pickup_chunks(
+    added: Query<&Position, Added<Pickup>>,
+    mut removed: RemovedComponents<Pickup>,
+    positions: Query<&Position>,
+    pickups: Query<(&Position, Has<PowerPill>), With<Pickup>>,
+    mut chunks: Query<(&TileChunk, &Mesh2dHandle, &mut Visibility), With<PickupChunk>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    paint: Res<MazePaint>,
+) {
+    let mut changed: Vec<_> = added.iter().cloned().collect();
+    // Despawned pickups can be anywhere
+    let mut everywhere = false;
+    for entity in removed.read() {
+        match positions.get(entity) {
+            Ok(position) => changed.push(position.clone()),
+            Err(_) => everywhere = true,
+        }
+    }
+    if changed.is_empty() && !everywhere {
+        return;
+    }
+
+    let tiles: Vec<_> = pickups
+        .iter()
+        .map(|(position, power_pill)| (position, get_pickup_tile(power_pill)))
+        .collect();
+    for (chunk, mesh, visibility) in &mut chunks {
+        if everywhere || changed.iter().any(|position| chunk.contains(position)) {
+            let tiles = tiles.iter().copied();
+            tilemap::fill_chunk(chunk, mesh, visibility, &mut meshes, tiles, &paint.0);
+        }
+    }
+}
+
+const fn get_pickup_tile(power_pill: bool) -> Tile {
+    if power_pill {
+        Tile::PowerPill
+    } else {
+        Tile::Dot
+    }
+}
+
+/// Remembers the pickups left on the board, in case the other player takes
+/// the next turn
+fn save_pickups(query: Query<&Position, With<Pickup>>, mut game_globals: ResMut<GameGlobals>) {
+    game_globals.get_player_mut().pickups = Some(query.iter().cloned().collect());
+}
+
+fn check_level_complete(
+    query: Query<(), With<Pickup>>,
+    mut next_state: ResMut<NextState<GameState>>,
+    tick: Res<SimulationTick>,
+) {
+    if query.is_empty() && tick.is_final() {
+        next_state.set(GameState::LevelComplete);
+    }
+}
+
+fn start_maze_flash(mut commands: Commands, animations: Res<GameAnimations>) {
+    commands.spawn((
+        SpriteSheetAnimator::new(animations.effects.clone()),
+        AnimationState::MazeFlash,
+        MazeFlash,
+    ));
+}
+
+fn stop_maze_flash(mut commands: Commands, flash_query: Query<Entity, With<MazeFlash>>) {
+    for entity in flash_query.iter() {
+        commands.entity(entity).despawn();
+    }
+}
+
 impl Board {
+    /// Cell at the given index of `cells`
+    const fn get_position(&self, index: usize) -> Position {
+        let columns = self.columns as usize;
+        Position::new((index % columns) as f32, (index / columns) as f32)
+    }
+
+    /// Index in `cells` of a cell within the board
+    const fn get_index(&self, pos: &Position) -> usize {
+        pos.y as usize * self.columns as usize + pos.x as usize
+    }
+
+    /// Tile of every wall and door
+    fn get_wall_tiles(&self) -> Vec<(Position, Tile)> {
+        self.cells
+            .iter()
+            .enumerate()
+            .filter(|(_, cell_type)| matches!(cell_type, CellType::Wall(_)))
+            .filter_map(|(index, cell_type)| {
+                Some((self.get_position(index), cell_type.get_tile()?))
+            })
+            .collect()
+    }
+
     pub fn get_cell(&self, pos: &Position) -> CellType {
         let Position { x, y } = *pos;
         if x < 0. || x >= self.columns as f32 {
@@ -125,6 +390,72 @@ impl Board {
     pub const fn get_enemies(&self) -> &Vec<Enemy> {
         &self.enemies
     }
+
+    /// Whether a player can get to the cell, enemies in their house are
+    /// out of reach until they come out
+    pub fn is_reachable(&self, pos: &Position) -> bool {
+        if self.get_cell(pos) == CellType::Outside {
+            return false;
+        }
+        self.reachable[self.get_index(pos)]
+    }
+
+    /// Spreads from every pickup through the cells that are not walls
+    fn find_reachable_cells(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.cells.len()];
+        let mut pending = self.get_pickup_cells();
+        while let Some(position) = pending.pop() {
+            let index = self.get_index(&position);
+            if reachable[index] {
+                continue;
+            }
+            reachable[index] = true;
+            for &dir in Directions::iterator() {
+                let next = position.get_target_cell(dir);
+                if !matches!(self.get_cell(&next), CellType::Wall(_) | CellType::Outside) {
+                    pending.push(next);
+                }
+            }
+        }
+        reachable
+    }
+
+    /// Whether the cell still holds its dot or power pill
+    pub fn has_pickup(&self, pos: &Position) -> bool {
+        matches!(self.get_cell(pos), CellType::Dot | CellType::PowerPill)
+    }
+
+    /// Empties the cell once its pickup is eaten, for boards that are not
+    /// played on
+    pub fn clear_pickup(&mut self, pos: &Position) {
+        if self.has_pickup(pos) {
+            let index = self.get_index(pos);
+            self.cells[index] = CellType::Empty;
+        }
+    }
+
+    pub fn count_cells(&self, cell_type: CellType) -> usize {
+        self.cells.iter().filter(|&&cell| cell == cell_type).count()
+    }
+
+    /// Cells holding a dot or a power pill when the level starts, row by row
+    pub fn get_pickup_cells(&self) -> Vec<Position> {
+        self.cells
+            .iter()
+            .enumerate()
+            .filter(|(_, cell)| matches!(cell, CellType::Dot | CellType::PowerPill))
+            .map(|(index, _)| {
+                let index = index as isize;
+                Position::new((index % self.columns) as f32, (index / self.columns) as f32)
+            })
+            .collect()
+    }
+}
+
+impl Default for Board {
+    fn default() -> Self {
+        DEFAULT_LAYOUT.into()
+    }
 }
 
 impl From<&str> for Board {
@@ -167,6 +498,7 @@ impl From<&str> for Board {
             rows: 0,
             cells: Vec::with_capacity(input.len()),
             enemies: vec![],
+            reachable: vec![],
         };
 
         for line in input.lines() {
@@ -197,7 +529,7 @@ impl From<&str> for Board {
                     '1' => {
                         board.enemies.push(Enemy::new(
                             Vec2::new(column as f32, board.rows as f32),
-                            EnemyAI::Random,
+                            EnemyAI::Chase,
                         ));
                         CellType::Empty
                     }
@@ -209,22 +541,23 @@ impl From<&str> for Board {
             }
             board.rows += 1;
         }
+        board.reachable = board.find_reachable_cells();
         board
     }
 }
 
 impl CellType {
-    const fn get_asset(self) -> Option<GameAssets> {
+    const fn get_tile(self) -> Option<Tile> {
         match self {
-            Self::Wall(WallType::Vertical) => Some(GameAssets::WallVertical),
-            Self::Wall(WallType::Horizontal) => Some(GameAssets::WallHorizontal),
-            Self::Wall(WallType::TopLeft) => Some(GameAssets::WallTopLeft),
-            Self::Wall(WallType::TopRight) => Some(GameAssets::WallTopRight),
-            Self::Wall(WallType::BottomLeft) => Some(GameAssets::WallBottomLeft),
-            Self::Wall(WallType::BottomRight) => Some(GameAssets::WallBottomRight),
-            Self::Wall(WallType::Door) => Some(GameAssets::Door),
-            Self::Dot => Some(GameAssets::Dot),
-            Self::PowerPill => Some(GameAssets::PowerPill),
+            Self::Wall(WallType::Vertical) => Some(Tile::WallVertical),
+            Self::Wall(WallType::Horizontal) => Some(Tile::WallHorizontal),
+            Self::Wall(WallType::TopLeft) => Some(Tile::WallTopLeft),
+            Self::Wall(WallType::TopRight) => Some(Tile::WallTopRight),
+            Self::Wall(WallType::BottomLeft) => Some(Tile::WallBottomLeft),
+            Self::Wall(WallType::BottomRight) => Some(Tile::WallBottomRight),
+            Self::Wall(WallType::Door) => Some(Tile::Door),
+            Self::Dot => Some(Tile::Dot),
+            Self::PowerPill => Some(Tile::PowerPill),
             Self::Empty | Self::Outside => None,
         }
     }
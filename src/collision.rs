@@ -1,10 +1,11 @@
 use crate::{
-    enemies::Enemy,
+    enemies::{Enemy, EnemyKilledEvent, EnemyMode},
     movement::Position,
-    pickup::{Dot, Pickup, PowerPill},
+    pickup::{Dot, Pickup, PickupEvent, PickupKind, PowerPill},
     player::Player,
+    simulation::{GameTick, RollbackApp, SimulationTick, TickSet},
     state::GameEvent,
-    state::{GameGlobals, GameState},
+    state::GameState,
 };
 use bevy::prelude::*;
 
@@ -12,14 +13,18 @@ pub struct CollisionPlugin;
 
 impl Plugin for CollisionPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(
-            Update,
-            player_collision_detection.run_if(in_state(GameState::Running)),
-        );
+        app.add_event::<PickupEvent>()
+            .rollback_component::<Collider>()
+            .add_systems(
+                GameTick,
+                player_collision_detection
+                    .in_set(TickSet::Collision)
+                    .run_if(in_state(GameState::Playing)),
+            );
     }
 }
 
-#[derive(Component)]
+#[derive(Component, Clone)]
 pub struct Collider {
     pub distance: f32,
 }
@@ -30,31 +35,83 @@ impl Collider {
     }
 }
 
-fn player_collision_detection(
+#[allow(clippy::too_many_arguments)]
+pub fn player_collision_detection(
     mut commnands: Commands,
     query_powerpill: Query<(Entity, &Position, &Collider, &Pickup), With<PowerPill>>,
     query_dot: Query<(Entity, &Position, &Collider, &Pickup), With<Dot>>,
-    query_enemy: Query<(&Position, &Collider), With<Enemy>>,
-    player_query: Query<&Position, With<Player>>,
-    mut game_globals: ResMut<GameGlobals>,
+    query_enemy: Query<(Entity, &Position, &Collider, &EnemyMode, &Enemy)>,
+    player_query: Query<(&Position, &Player)>,
+    mut pickup_writer: EventWriter<PickupEvent>,
+    mut enemy_killed_writer: EventWriter<EnemyKilledEvent>,
     mut event_writer: EventWriter<GameEvent>,
+    tick: Res<SimulationTick>,
 ) {
-    let player_position = player_query.get_single().unwrap();
-    for (entity, position, collider, pickup) in query_powerpill.iter() {
-        if player_position.get_distance(position) < collider.distance {
-            game_globals.score += pickup.get_value();
-            commnands.entity(entity).despawn_recursive();
+    // Whatever two players reach in the same tick goes to the first one
+    let mut players: Vec<_> = player_query.iter().collect();
+    players.sort_by_key(|(_, player)| player.index);
+    let mut taken = Vec::new();
+    for (player_position, player) in players {
+        for (entity, position, collider, pickup) in query_powerpill.iter() {
+            if player_position.get_distance(position) < collider.distance
+                && !taken.contains(&entity)
+            {
+                pickup_writer.send(PickupEvent {
+                    player: player.index,
+                    value: pickup.get_value(),
+                    kind: PickupKind::PowerPill,
+                    position: position.clone(),
+                });
+                hide_pickup(&mut commnands, entity);
+                taken.push(entity);
+            }
         }
-    }
-    for (entity, position, collider, pickup) in query_dot.iter() {
-        if player_position.get_distance(position) < collider.distance {
-            game_globals.score += pickup.get_value();
-            commnands.entity(entity).despawn_recursive();
+        for (entity, position, collider, pickup) in query_dot.iter() {
+            if player_position.get_distance(position) < collider.distance
+                && !taken.contains(&entity)
+            {
+                pickup_writer.send(PickupEvent {
+                    player: player.index,
+                    value: pickup.get_value(),
+                    kind: PickupKind::Dot,
+                    position: position.clone(),
+                });
+                hide_pickup(&mut commnands, entity);
+                taken.push(entity);
+            }
         }
-    }
-    for (position, collider) in query_enemy.iter() {
-        if player_position.get_distance(position) < collider.distance {
-            event_writer.send(GameEvent::PlayerDies);
+        for (entity, position, collider, mode, enemy) in query_enemy.iter() {
+            if player_position.get_distance(position) >= collider.distance {
+                continue;
+            }
+            match mode {
+                EnemyMode::Frightened => {
+                    if !taken.contains(&entity) {
+                        enemy_killed_writer.send(EnemyKilledEvent {
+                            entity,
+                            player: player.index,
+                            position: Position::new(position.x, position.y),
+                        });
+                        taken.push(entity);
+                    }
+                }
+                EnemyMode::Chasing => {
+                    if tick.is_final() {
+                        event_writer.send(GameEvent::PlayerDies {
+                            player: player.index,
+                            caught_by: enemy.get_driver(),
+                            position: player_position.clone(),
+                        });
+                    }
+                }
+                EnemyMode::Eaten => (),
+            }
         }
     }
 }
+
+/// Eaten pickups stay around, no longer drawn, until the board is filled
+/// again so a rollback can bring them back
+fn hide_pickup(commands: &mut Commands, entity: Entity) {
+    commands.entity(entity).remove::<(Pickup, Collider)>();
+}
@@ -1,4 +1,4 @@
-use crate::{board::Board, state::GameGlobals, CELL_SIZE};
+use crate::{board::Board, collision::Collider, state::GameGlobals, CELL_SIZE};
 use bevy::prelude::*;
 
 pub struct DebugPlugin;
@@ -17,12 +17,33 @@ fn setup(mut config_store: ResMut<GizmoConfigStore>) {
     config.line_width = 0.1;
 }
 
-fn draw_debug(gizmos: Gizmos<DebugGizmos>, board: Res<Board>, game_globals: Res<GameGlobals>) {
+fn draw_debug(
+    mut gizmos: Gizmos<DebugGizmos>,
+    board: Res<Board>,
+    game_globals: Res<GameGlobals>,
+    colliders: Query<(&Transform, &Collider)>,
+) {
+    if game_globals.is_debug {
+        draw_colliders(&colliders, &mut gizmos);
+    }
     if game_globals.show_grid {
         draw_grid(board, gizmos);
     }
 }
 
+fn draw_colliders(
+    colliders: &Query<(&Transform, &Collider)>,
+    gizmos: &mut Gizmos<'_, '_, DebugGizmos>,
+) {
+    for (transform, collider) in colliders.iter() {
+        gizmos.circle_2d(
+            transform.translation.truncate(),
+            collider.distance * CELL_SIZE,
+            Color::RED,
+        );
+    }
+}
+
 fn draw_grid(board: Res<'_, Board>, mut gizmos: Gizmos<'_, '_, DebugGizmos>) {
     let (rows, columns) = board.get_dimensions();
     for row in 1..rows {
@@ -0,0 +1,172 @@
+use crate::{
+    movement::Position,
+    pickup::{PickupEvent, PickupKind},
+    simulation::{FinalTickEvents, GameTick, TickSet},
+    state::{GameEvent, GameGlobals, GameState},
+    storage,
+    theme::Palette,
+    CELL_SIZE,
+};
+use bevy::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+const EFFECTS_STORAGE: &str = "effects";
+const PARTICLE_COUNT: usize = 12;
+/// Side of a particle, in cells
+const PARTICLE_SIZE: f32 = 0.2;
+/// Cells per second
+const PARTICLE_MIN_SPEED: f32 = 2.;
+const PARTICLE_MAX_SPEED: f32 = 5.;
+/// Seconds for a particle to fade out
+const PARTICLE_LIFETIME: f32 = 0.6;
+const DEATH_BURST_COLOR: Color = Color::YELLOW;
+/// Cells per second the score popups rise
+const POPUP_RISE_SPEED: f32 = 1.;
+/// Particles are drawn over the characters, under the popups
+const PARTICLE_DEPTH: f32 = 5.;
+
+/// Bursts of particles and floating score popups, none of them part of the
+/// simulation
+pub struct EffectsPlugin;
+
+impl Plugin for EffectsPlugin {
+    fn build(&self, app: &mut App) {
+        let settings = storage::load(EFFECTS_STORAGE).unwrap_or_default();
+        app.insert_resource::<EffectsSettings>(settings)
+            .add_systems(
+                GameTick,
+                burst_power_pills
+                    .after(TickSet::Collision)
+                    .run_if(in_state(GameState::Playing)),
+            )
+            .add_systems(
+                Update,
+                (
+                    burst_deaths.run_if(on_event::<GameEvent>()),
+                    update_particles,
+                    float_popups,
+                    save_effects_settings.run_if(
+                        resource_changed::<EffectsSettings>
+                            .and_then(not(resource_added::<EffectsSettings>)),
+                    ),
+                ),
+            );
+    }
+}
+
+#[derive(Resource, Default, Serialize, Deserialize)]
+pub struct EffectsSettings {
+    /// No particles, and the score popups stay still
+    pub reduced_motion: bool,
+}
+
+/// Rises while it is shown
+#[derive(Component)]
+pub struct FloatingText;
+
+#[derive(Component)]
+struct Particle {
+    /// Cells per second
+    velocity: Vec2,
+    lifetime: Timer,
+}
+
+fn spawn_burst(commands: &mut Commands, position: &Position, color: Color) {
+    let mut rng = rand::thread_rng();
+    let mut transform = position.get_transform();
+    transform.translation.z = PARTICLE_DEPTH;
+    for index in 0..PARTICLE_COUNT {
+        let angle = index as f32 / PARTICLE_COUNT as f32 * std::f32::consts::TAU;
+        let speed = rng.gen_range(PARTICLE_MIN_SPEED..PARTICLE_MAX_SPEED);
+        commands.spawn((
+            SpriteBundle {
+                sprite: Sprite {
+                    color,
+                    custom_size: Some(Vec2::splat(PARTICLE_SIZE * CELL_SIZE)),
+                    ..Default::default()
+                },
+                transform,
+                ..Default::default()
+            },
+            Particle {
+                velocity: Vec2::from_angle(angle) * speed,
+                lifetime: Timer::from_seconds(PARTICLE_LIFETIME, TimerMode::Once),
+            },
+        ));
+    }
+}
+
+/// Power pills burst in the colour of the dots
+fn burst_power_pills(
+    mut commands: Commands,
+    mut pickups: FinalTickEvents<PickupEvent>,
+    settings: Res<EffectsSettings>,
+    palette: Res<Palette>,
+    game_globals: Res<GameGlobals>,
+) {
+    let pickups = pickups.read();
+    if settings.reduced_motion {
+        return;
+    }
+    let color = palette
+        .get_maze_colors(game_globals.get_player().level)
+        .dots;
+    for pickup in pickups {
+        if pickup.kind != PickupKind::PowerPill {
+            continue;
+        }
+        spawn_burst(&mut commands, &pickup.position, color);
+    }
+}
+
+fn burst_deaths(
+    mut commands: Commands,
+    mut event_reader: EventReader<GameEvent>,
+    settings: Res<EffectsSettings>,
+) {
+    for event in event_reader.read() {
+        if let GameEvent::PlayerDies { position, .. } = event {
+            if !settings.reduced_motion {
+                spawn_burst(&mut commands, position, DEATH_BURST_COLOR);
+            }
+        }
+    }
+}
+
+/// Particles fly apart and fade out, they stop with the game time
+fn update_particles(
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut Particle, &mut Transform, &mut Sprite)>,
+    time: Res<Time>,
+) {
+    for (entity, mut particle, mut transform, mut sprite) in &mut query {
+        particle.lifetime.tick(time.delta());
+        if particle.lifetime.finished() {
+            commands.entity(entity).despawn();
+            continue;
+        }
+        let distance = particle.velocity * CELL_SIZE * time.delta_seconds();
+        transform.translation += distance.extend(0.);
+        sprite.color.set_a(1. - particle.lifetime.fraction());
+    }
+}
+
+/// Popups are shown while the game time is frozen, so they rise with the
+/// real time
+fn float_popups(
+    mut query: Query<&mut Transform, With<FloatingText>>,
+    settings: Res<EffectsSettings>,
+    time: Res<Time<Real>>,
+) {
+    if settings.reduced_motion {
+        return;
+    }
+    for mut transform in &mut query {
+        transform.translation.y += POPUP_RISE_SPEED * CELL_SIZE * time.delta_seconds();
+    }
+}
+
+fn save_effects_settings(settings: Res<EffectsSettings>) {
+    storage::save(EFFECTS_STORAGE, &*settings);
+}
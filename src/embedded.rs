@@ -0,0 +1,61 @@
+use bevy::{
+    asset::io::{
+        memory::{Dir, MemoryAssetReader},
+        AssetSource, AssetSourceId,
+    },
+    prelude::*,
+};
+use std::path::Path;
+
+/// Puts every file under `assets` the game loads in the given folder,
+/// keeping the same path
+macro_rules! embed {
+    ($dir:expr, $($path:literal),* $(,)?) => {
+        $(
+            $dir.insert_asset(
+                Path::new($path),
+                &include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/", $path))[..],
+            );
+        )*
+    };
+}
+
+/// Reads the assets from the binary instead of the `assets` folder, or the
+/// web server. Has to be added before the `AssetPlugin`.
+pub struct EmbeddedAssetsPlugin;
+
+impl Plugin for EmbeddedAssetsPlugin {
+    fn build(&self, app: &mut App) {
+        let dir = Dir::default();
+        // The sprites are drawn from the packed atlas only
+        embed!(
+            dir,
+            "sprites/sprites.png",
+            "sprites/sprites.atlas.ron",
+            "fonts/pixelplay.ttf",
+            "animations/player.anim.ron",
+            "animations/enemy.anim.ron",
+            "animations/enemy-eyes.anim.ron",
+            "animations/effects.anim.ron",
+            "themes/index.themes.ron",
+            "themes/high-contrast/theme.ron",
+        );
+        #[cfg(feature = "audio")]
+        embed!(
+            dir,
+            "sounds/waka.ogg",
+            "sounds/enemy-eaten.ogg",
+            "sounds/death.ogg",
+            "sounds/extra-life.ogg",
+            "sounds/intro.ogg",
+            "sounds/siren.ogg",
+            "sounds/power-pill-siren.ogg",
+        );
+
+        app.register_asset_source(
+            AssetSourceId::Default,
+            AssetSource::build()
+                .with_reader(move || Box::new(MemoryAssetReader { root: dir.clone() })),
+        );
+    }
+}
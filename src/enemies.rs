@@ -1,59 +1,183 @@
 use crate::{
-    board::{Board, CellType},
+    board::{Board, CellType, WallType},
     collision::Collider,
-    game_assets::{GameAssets, GameAssetsLoader},
-    movement::{Direction, MovableObject, MovingObjectBundle, Position, Velocity},
-    spritesheet::{AnimatedSpriteBundle, AnimationStrategy, SpriteSheetAnimator},
-    ENEMY_RANGE, PLAYER_VELOCITY, STARTING_DIRECTION,
+    game_assets::{GameAnimations, GameAssetsLoader},
+    movement::{Direction, Directions, MovableObject, MovingObjectBundle, Position, Velocity},
+    pickup::{PickupEvent, PickupKind},
+    player::Player,
+    simulation::{GameTick, Rollback, RollbackApp, SimRng, TickInputs, TickSet},
+    spritesheet::{AnimatedSpriteBundle, AnimationState, SpriteSheetAnimator},
+    state::{GameEvent, GameGlobals, GameMode, GameState},
+    theme::Palette,
+    ENEMY_RANGE, EYES_RANGE, EYES_VELOCITY, FRIGHTENED_FLASH_TIME, FRIGHTENED_TIME,
+    FRIGHTENED_VELOCITY, PLAYER_VELOCITY, STARTING_DIRECTION,
 };
 use bevy::prelude::*;
-use rand::{seq::SliceRandom, thread_rng};
+use rand::Rng;
 
 pub struct EnemiesPlugin;
 
 impl Plugin for EnemiesPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, spawn_enemies);
+        app.add_event::<EnemyKilledEvent>()
+            .insert_resource(FrightenedTimer(Timer::from_seconds(
+                FRIGHTENED_TIME,
+                TimerMode::Once,
+            )))
+            .rollback_component::<Enemy>()
+            .rollback_component::<EnemyMode>()
+            .rollback_resource::<FrightenedTimer>()
+            .add_systems(OnExit(GameState::Loading), spawn_enemies)
+            .add_systems(OnEnter(GameState::Ready), reset_enemies)
+            .add_systems(
+                Update,
+                (
+                    assign_enemy_drivers.run_if(on_event::<GameEvent>()),
+                    update_enemy_animation,
+                ),
+            )
+            .add_systems(
+                GameTick,
+                enemy_movement_control
+                    .in_set(TickSet::Input)
+                    .run_if(in_state(GameState::Ready).or_else(in_state(GameState::Playing))),
+            )
+            .add_systems(
+                GameTick,
+                (
+                    (target_nearest_player, roll_enemy_dice).in_set(TickSet::Input),
+                    update_frightened_timer.in_set(TickSet::Movement),
+                    (
+                        frighten_enemies.run_if(on_event::<PickupEvent>()),
+                        send_home_killed_enemies.run_if(on_event::<EnemyKilledEvent>()),
+                        bring_back_eaten_enemies,
+                    )
+                        .in_set(TickSet::Outcome),
+                )
+                    .run_if(in_state(GameState::Playing)),
+            );
     }
 }
 
 #[derive(Component, Clone, Copy)]
 pub struct Enemy {
     start_position: Vec2,
-    enemy_ai: EnemyAI,
+    ai: EnemyAI,
+    /// Cell the enemy is heading for, if any
+    target: Option<Vec2>,
+    /// Random number drawn every tick, used to pick a way when there is
+    /// nobody to chase
+    roll: u32,
+    /// Heading back to the starting cell after being eaten, doors let it
+    /// through
+    homing: bool,
 }
 
 impl Enemy {
-    pub const fn new(start_position: Vec2, _enemy_ai: EnemyAI) -> Self {
+    pub const fn new(start_position: Vec2, enemy_ai: EnemyAI) -> Self {
         Self {
             start_position,
-            enemy_ai: EnemyAI::Random,
+            ai: enemy_ai,
+            target: None,
+            roll: 0,
+            homing: false,
+        }
+    }
+
+    pub const fn get_start_position(&self) -> Vec2 {
+        self.start_position
+    }
+
+    /// Player driving the enemy, if any
+    pub const fn get_driver(&self) -> Option<usize> {
+        match self.ai {
+            EnemyAI::Human { player } => Some(player),
+            EnemyAI::Chase => None,
         }
     }
 }
 
 #[derive(Clone, Copy)]
 pub enum EnemyAI {
-    Random,
+    /// Takes the way closest to the nearest player at every crossing, and a
+    /// random one when there is nobody to chase
+    Chase,
+    /// Driven by the given player, still without turning back or going
+    /// through walls and doors
+    Human { player: usize },
 }
 
+/// Frightened enemies run slower and can be eaten by the player, eaten ones
+/// run back to their starting cell as a pair of eyes
+#[derive(Component, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EnemyMode {
+    #[default]
+    Chasing,
+    Frightened,
+    Eaten,
+}
+
+/// Index in the palette of the body colour while the enemy chases
+#[derive(Component)]
+struct EnemyColor(usize);
+
+/// Drawn over the body, looking where the enemy goes
+#[derive(Component)]
+struct EnemyEyes;
+
+/// Sent when a player eats a frightened enemy
+#[derive(Event)]
+pub struct EnemyKilledEvent {
+    pub entity: Entity,
+    pub player: usize,
+    pub position: Position,
+}
+
+#[derive(Resource, Clone)]
+struct FrightenedTimer(Timer);
+
 impl MovableObject for Enemy {
     fn update_direction(&self, pos: &Position, dir: &mut Direction, board: &Board) {
-        dir.current = match self.enemy_ai {
-            EnemyAI::Random => {
-                // If the enemy is transitioning through outside of the
-                // board(using one tunnel), it cannot change the direction
-                let target = board.get_cell(&pos.get_target_cell(dir.current));
-                let current = board.get_cell(pos);
-                if target == CellType::Outside || current == CellType::Outside {
-                    return;
-                };
+        // If the enemy is transitioning through outside of the
+        // board(using one tunnel), it cannot change the direction
+        let target = board.get_cell(&pos.get_target_cell(dir.current));
+        let current = board.get_cell(pos);
+        if target == CellType::Outside || current == CellType::Outside {
+            return;
+        }
 
-                let mut directions = board.get_neighbours(pos.x, pos.y);
-                directions.retain(|(_, cell)| !matches!(cell, CellType::Wall(_)));
-                directions.retain(|(new_dir, _)| !dir.is_opposite(*new_dir));
-                let mut rng = thread_rng();
-                directions.choose(&mut rng).unwrap().0
+        let mut directions = board.get_neighbours(pos.x, pos.y);
+        directions.retain(|(_, cell)| match cell {
+            CellType::Wall(WallType::Door) => self.homing,
+            CellType::Wall(_) => false,
+            _ => true,
+        });
+        directions.retain(|(new_dir, _)| !dir.is_opposite(*new_dir));
+        // Dead ends, and cells only left through a door the enemy may not
+        // use, leave no way but back
+        let back = dir.current.opposite();
+        dir.current = match self.ai {
+            // The requested way is taken when it is open, otherwise the enemy
+            // keeps going and only turns by itself on corners
+            EnemyAI::Human { .. } if !self.homing => [dir.next, dir.current]
+                .into_iter()
+                .find(|wanted| directions.iter().any(|(open, _)| open == wanted))
+                .or_else(|| directions.first().map(|(open, _)| *open))
+                .unwrap_or(back),
+            EnemyAI::Chase | EnemyAI::Human { .. } => {
+                let choice = self.target.map_or_else(
+                    || directions.get(self.roll as usize % directions.len().max(1)),
+                    |target| {
+                        directions.iter().min_by(|(a, _), (b, _)| {
+                            let distance = |direction: Directions| {
+                                let cell = pos.get_target_cell(direction);
+                                Vec2::new(cell.x, cell.y).distance_squared(target)
+                            };
+                            distance(*a).total_cmp(&distance(*b))
+                        })
+                    },
+                );
+                choice.map_or(back, |(direction, _)| *direction)
             }
         }
     }
@@ -62,47 +186,305 @@ impl MovableObject for Enemy {
 fn spawn_enemies(
     mut commands: Commands,
     game_assets: Res<GameAssetsLoader>,
-    mut texture_atlas_layouts: ResMut<Assets<TextureAtlasLayout>>,
+    animations: Res<GameAnimations>,
     board: Res<Board>,
+    palette: Res<Palette>,
 ) {
-    for enemy in board.get_enemies() {
+    for (index, enemy) in board.get_enemies().iter().enumerate() {
+        let color = palette.get_enemy_color(index);
         let position = Position::new(enemy.start_position.x, enemy.start_position.y);
         let transform = Transform::from(&position);
-        let layout = TextureAtlasLayout::from_grid(Vec2::new(24., 24.), 3, 1, None, None);
-        let texture_atlas_layout = texture_atlas_layouts.add(layout);
-        commands.spawn((
-            MovingObjectBundle {
-                position,
-                velocity: Velocity::new(PLAYER_VELOCITY),
-                dir: Direction::new(STARTING_DIRECTION, STARTING_DIRECTION),
-            },
-            AnimatedSpriteBundle {
-                sprite_sheet_animator: SpriteSheetAnimator {
-                    start: 0,
-                    end: 2,
-                    frame_rate: 10.,
-                    strategy: AnimationStrategy::PingPong,
-                    texture_atlas_layout: texture_atlas_layout.clone(),
-                    ..Default::default()
+        commands
+            .spawn((
+                MovingObjectBundle {
+                    position,
+                    velocity: Velocity::new(PLAYER_VELOCITY),
+                    dir: Direction::new(STARTING_DIRECTION, STARTING_DIRECTION),
                 },
-                spritesheet_bundle: SpriteSheetBundle {
-                    texture: game_assets.get(GameAssets::Blinkus),
-                    sprite: Sprite {
-                        anchor: bevy::sprite::Anchor::Center,
+                AnimatedSpriteBundle {
+                    sprite_sheet_animator: SpriteSheetAnimator::new(animations.enemy.clone()),
+                    animation_state: AnimationState::Walk(STARTING_DIRECTION),
+                    spritesheet_bundle: SpriteSheetBundle {
+                        texture: game_assets.get_atlas(),
+                        sprite: Sprite {
+                            anchor: bevy::sprite::Anchor::Center,
+                            color,
+                            ..Default::default()
+                        },
+                        transform,
                         ..Default::default()
                     },
-                    atlas: TextureAtlas {
-                        layout: texture_atlas_layout,
-                        index: 0,
-                    },
-                    transform,
-                    ..Default::default()
                 },
-            },
-            Collider {
-                distance: ENEMY_RANGE,
-            },
-            *enemy,
-        ));
+                Collider {
+                    distance: ENEMY_RANGE,
+                },
+                EnemyMode::default(),
+                EnemyColor(index),
+                *enemy,
+                Rollback,
+            ))
+            .with_children(|parent| {
+                parent.spawn((
+                    AnimatedSpriteBundle {
+                        sprite_sheet_animator: SpriteSheetAnimator::new(
+                            animations.enemy_eyes.clone(),
+                        ),
+                        animation_state: AnimationState::Eyes(STARTING_DIRECTION),
+                        spritesheet_bundle: SpriteSheetBundle {
+                            texture: game_assets.get_atlas(),
+                            transform: Transform::from_xyz(0., 0., 0.1),
+                            ..Default::default()
+                        },
+                    },
+                    EnemyEyes,
+                ));
+            });
+    }
+}
+
+/// In versus games the second player takes over the first enemy of the
+/// board, the rest keep the AI they were given
+fn assign_enemy_drivers(
+    mut query: Query<&mut Enemy>,
+    mut event_reader: EventReader<GameEvent>,
+    board: Res<Board>,
+) {
+    for event in event_reader.read() {
+        let GameEvent::NewGame { mode, .. } = event else {
+            continue;
+        };
+        for mut enemy in &mut query {
+            let Some(index) = board
+                .get_enemies()
+                .iter()
+                .position(|other| other.start_position == enemy.start_position)
+            else {
+                continue;
+            };
+            enemy.ai = if *mode == GameMode::Versus && index == 0 {
+                EnemyAI::Human { player: 1 }
+            } else {
+                board.get_enemies()[index].ai
+            };
+        }
     }
 }
+
+fn enemy_movement_control(
+    mut query: Query<(&Enemy, &mut Direction)>,
+    inputs: Res<TickInputs>,
+    game_globals: Res<GameGlobals>,
+) {
+    if game_globals.mode != GameMode::Versus {
+        return;
+    }
+    for (enemy, mut direction) in &mut query {
+        let requested = enemy
+            .get_driver()
+            .and_then(|player| inputs.directions[player]);
+        if let Some(requested) = requested {
+            direction.next = requested;
+        }
+    }
+}
+
+/// Chasing enemies go after the closest player, frightened ones wander and
+/// eaten ones head home.
+/// Players at the same distance are told apart by their index, so every
+/// instance of a network game picks the same one.
+fn target_nearest_player(
+    mut query: Query<(&mut Enemy, &Position, &EnemyMode)>,
+    players: Query<(&Position, &Player)>,
+) {
+    for (mut enemy, position, mode) in &mut query {
+        enemy.homing = *mode == EnemyMode::Eaten;
+        enemy.target = match mode {
+            EnemyMode::Chasing => players
+                .iter()
+                .min_by(|(a, a_player), (b, b_player)| {
+                    position
+                        .get_distance(a)
+                        .total_cmp(&position.get_distance(b))
+                        .then(a_player.index.cmp(&b_player.index))
+                })
+                .map(|(player, _)| Vec2::new(player.x, player.y)),
+            EnemyMode::Frightened => None,
+            EnemyMode::Eaten => Some(enemy.start_position),
+        };
+    }
+}
+
+/// Entities are not visited in the same order by every instance, so the
+/// numbers are handed out by starting cell
+fn roll_enemy_dice(mut query: Query<&mut Enemy>, mut rng: ResMut<SimRng>) {
+    let mut enemies: Vec<_> = query.iter_mut().collect();
+    enemies.sort_by(|a, b| {
+        a.start_position
+            .x
+            .total_cmp(&b.start_position.x)
+            .then(a.start_position.y.total_cmp(&b.start_position.y))
+    });
+    for enemy in &mut enemies {
+        enemy.roll = rng.0.gen();
+    }
+}
+
+fn frighten_enemies(
+    mut query: Query<(&mut EnemyMode, &mut Velocity, &mut Direction), With<Enemy>>,
+    mut pickup_reader: EventReader<PickupEvent>,
+    mut frightened_timer: ResMut<FrightenedTimer>,
+) {
+    let power_pill_eaten = pickup_reader
+        .read()
+        .any(|pickup| pickup.kind == PickupKind::PowerPill);
+    if !power_pill_eaten {
+        return;
+    }
+
+    frightened_timer.0.reset();
+    for (mut mode, mut velocity, mut direction) in &mut query {
+        // Enemies turn around the moment they get frightened
+        match *mode {
+            EnemyMode::Chasing => {
+                direction.current = direction.current.opposite();
+                direction.next = direction.current;
+            }
+            EnemyMode::Frightened => (),
+            EnemyMode::Eaten => continue,
+        }
+        *mode = EnemyMode::Frightened;
+        *velocity = Velocity::new(FRIGHTENED_VELOCITY);
+    }
+}
+
+/// Frightened enemies have a face of their own instead of the eyes, and
+/// blink in the flash colour before they recover. Eaten ones are only eyes.
+#[allow(clippy::type_complexity)]
+fn update_enemy_animation(
+    mut query: Query<
+        (
+            &Direction,
+            &EnemyMode,
+            &EnemyColor,
+            &SpriteSheetAnimator,
+            &Children,
+            &mut AnimationState,
+            &mut Sprite,
+            &mut Visibility,
+        ),
+        With<Enemy>,
+    >,
+    mut eyes_query: Query<
+        (&mut AnimationState, &mut Visibility),
+        (With<EnemyEyes>, Without<Enemy>),
+    >,
+    frightened_timer: Res<FrightenedTimer>,
+    palette: Res<Palette>,
+) {
+    let flashing = frightened_timer.0.remaining_secs() < FRIGHTENED_FLASH_TIME;
+    for (direction, mode, color, animator, children, mut body, mut sprite, mut body_visibility) in
+        &mut query
+    {
+        let (next, color) = match mode {
+            EnemyMode::Frightened if flashing => {
+                let color = if animator.frame.is_multiple_of(2) {
+                    palette.frightened
+                } else {
+                    palette.frightened_flash
+                };
+                (AnimationState::Flashing, color)
+            }
+            EnemyMode::Frightened => (AnimationState::Frightened, palette.frightened),
+            EnemyMode::Chasing | EnemyMode::Eaten => (
+                AnimationState::Walk(direction.current),
+                palette.get_enemy_color(color.0),
+            ),
+        };
+        body.set_if_neq(next);
+        if sprite.color != color {
+            sprite.color = color;
+        }
+        body_visibility.set_if_neq(if *mode == EnemyMode::Eaten {
+            Visibility::Hidden
+        } else {
+            Visibility::Inherited
+        });
+
+        for child in children {
+            let Ok((mut eyes, mut eyes_visibility)) = eyes_query.get_mut(*child) else {
+                continue;
+            };
+            eyes.set_if_neq(AnimationState::Eyes(direction.current));
+            // The body is hidden, so the eyes are shown on their own
+            eyes_visibility.set_if_neq(match mode {
+                EnemyMode::Frightened => Visibility::Hidden,
+                EnemyMode::Chasing => Visibility::Inherited,
+                EnemyMode::Eaten => Visibility::Visible,
+            });
+        }
+    }
+}
+
+fn update_frightened_timer(
+    mut query: Query<(&mut EnemyMode, &mut Velocity), With<Enemy>>,
+    mut frightened_timer: ResMut<FrightenedTimer>,
+    time: Res<Time>,
+) {
+    if !frightened_timer.0.tick(time.delta()).just_finished() {
+        return;
+    }
+    for (mut mode, mut velocity) in &mut query {
+        if *mode == EnemyMode::Frightened {
+            calm_down(&mut mode, &mut velocity);
+        }
+    }
+}
+
+/// Eaten enemies leave their body behind and run back home
+fn send_home_killed_enemies(
+    mut query: Query<(&mut EnemyMode, &mut Velocity)>,
+    mut event_reader: EventReader<EnemyKilledEvent>,
+) {
+    for event in event_reader.read() {
+        let Ok((mut mode, mut velocity)) = query.get_mut(event.entity) else {
+            continue;
+        };
+        *mode = EnemyMode::Eaten;
+        *velocity = Velocity::new(EYES_VELOCITY);
+    }
+}
+
+/// Eaten enemies chase again once they are back on their starting cell
+fn bring_back_eaten_enemies(
+    mut query: Query<(&Enemy, &mut EnemyMode, &mut Velocity, &mut Position)>,
+) {
+    for (enemy, mut mode, mut velocity, mut position) in &mut query {
+        let start = Position::new(enemy.start_position.x, enemy.start_position.y);
+        if *mode != EnemyMode::Eaten || position.get_distance(&start) > EYES_RANGE {
+            continue;
+        }
+        *position = start;
+        calm_down(&mut mode, &mut velocity);
+    }
+}
+
+fn reset_enemies(
+    mut query: Query<(
+        &Enemy,
+        &mut EnemyMode,
+        &mut Velocity,
+        &mut Position,
+        &mut Direction,
+    )>,
+) {
+    for (enemy, mut mode, mut velocity, mut position, mut direction) in &mut query {
+        *position = Position::new(enemy.start_position.x, enemy.start_position.y);
+        *direction = Direction::new(STARTING_DIRECTION, STARTING_DIRECTION);
+        calm_down(&mut mode, &mut velocity);
+    }
+}
+
+const fn calm_down(mode: &mut EnemyMode, velocity: &mut Velocity) {
+    *mode = EnemyMode::Chasing;
+    *velocity = Velocity::new(PLAYER_VELOCITY);
+}
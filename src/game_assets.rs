@@ -1,106 +1,278 @@
-use bevy::{prelude::*, utils::HashMap};
+use crate::{
+    atlas::{AtlasManifest, ATLAS, ATLAS_MANIFEST},
+    spritesheet::AnimationSet,
+    state::GameState,
+};
+use bevy::{
+    asset::{AssetLoadFailedEvent, LoadState, RecursiveDependencyLoadState, UntypedAssetId},
+    prelude::*,
+    render::{
+        render_asset::RenderAssetUsages,
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+    },
+};
+use serde::{Deserialize, Serialize};
 
+// Sprites packed into the atlas by `--pack-atlas`
 const PLAYER: &str = "sprites/capman.png";
 const BLINKUS: &str = "sprites/blinkus.png";
-const WALLVERTICAL: &str = "sprites/vertical.png";
-const WALLHORIZONTAL: &str = "sprites/horizontal.png";
-const WALLTOPLEFT: &str = "sprites/top-left.png";
-const WALLTOPRIGHT: &str = "sprites/top-right.png";
-const WALLBOTTOMLEFT: &str = "sprites/bottom-left.png";
-const WALLBOTTOMRIGHT: &str = "sprites/bottom-right.png";
-const DOOR: &str = "sprites/door.png";
-const DOT: &str = "sprites/dot.png";
-const POWERPILL: &str = "sprites/powerpill.png";
-const UI_FONT: &str = "fonts/pixelplay.ttf";
+const ENEMY_EYES: &str = "sprites/enemy-eyes.png";
+/// Walls, door and pickups, packed side by side in the order of [`Tile`]
+///
+/// [`Tile`]: crate::tilemap::Tile
+const MAZE_TILES: [&str; 9] = [
+    "sprites/vertical.png",
+    "sprites/horizontal.png",
+    "sprites/top-left.png",
+    "sprites/top-right.png",
+    "sprites/bottom-left.png",
+    "sprites/bottom-right.png",
+    "sprites/door.png",
+    "sprites/dot.png",
+    "sprites/powerpill.png",
+];
+pub const UI_FONT: &str = "fonts/pixelplay.ttf";
+const PLAYER_ANIMATIONS: &str = "animations/player.anim.ron";
+const ENEMY_ANIMATIONS: &str = "animations/enemy.anim.ron";
+const ENEMY_EYES_ANIMATIONS: &str = "animations/enemy-eyes.anim.ron";
+const EFFECTS_ANIMATIONS: &str = "animations/effects.anim.ron";
+/// Side of the checkerboard drawn instead of a broken image, in pixels
+const PLACEHOLDER_SIZE: u32 = 256;
+const PLACEHOLDER_SQUARE: u32 = 8;
+const PLACEHOLDER_COLORS: [[u8; 4]; 2] = [[255, 0, 255, 255], [0, 0, 0, 255]];
 
 pub struct AssetLoaderPlugin;
 
 impl Plugin for AssetLoaderPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<GameAssetsLoader>()
-            .add_systems(PreStartup, load_assets);
+            .init_resource::<LoadingQueue>()
+            .add_systems(PreStartup, load_assets)
+            .add_systems(
+                Update,
+                (
+                    finish_loading.run_if(in_state(GameState::Loading)),
+                    replace_failed::<Image>(|_| Some(build_placeholder_image())),
+                    replace_failed::<AtlasManifest>(|_| {
+                        Some(AtlasManifest::new_placeholder(Vec2::splat(
+                            PLACEHOLDER_SIZE as f32,
+                        )))
+                    }),
+                    replace_failed::<AnimationSet>(|_| Some(AnimationSet::default())),
+                    // Text is written with the font built in Bevy
+                    replace_failed::<Font>(|fonts| fonts.get(Handle::default()).cloned()),
+                    update_sprite_atlas.run_if(
+                        resource_changed::<GameAssetsLoader>
+                            .and_then(not(resource_added::<GameAssetsLoader>)),
+                    ),
+                ),
+            );
     }
 }
 
-#[derive(Eq, Hash, PartialEq, Clone, Copy)]
+#[derive(Eq, Hash, PartialEq, Ord, PartialOrd, Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum GameAssets {
     Player,
     Blinkus,
-    WallVertical,
-    WallHorizontal,
-    WallTopLeft,
-    WallTopRight,
-    WallBottomLeft,
-    WallBottomRight,
-    Dot,
-    PowerPill,
-    Door,
+    EnemyEyes,
+    Maze,
 }
 
-#[derive(Resource)]
+#[derive(Resource, PartialEq, Eq)]
 pub struct UiFont {
     pub default: Handle<Font>,
 }
 
+#[derive(Resource)]
+pub struct GameAnimations {
+    pub player: Handle<AnimationSet>,
+    pub enemy: Handle<AnimationSet>,
+    pub enemy_eyes: Handle<AnimationSet>,
+    /// Clips that only keep time, without a sprite sheet
+    pub effects: Handle<AnimationSet>,
+}
+
 impl GameAssets {
-    fn iterator() -> std::slice::Iter<'static, Self> {
-        static GAME_ASSETS: [GameAssets; 11] = [
+    pub fn iterator() -> std::slice::Iter<'static, Self> {
+        static GAME_ASSETS: [GameAssets; 4] = [
             GameAssets::Player,
             GameAssets::Blinkus,
-            GameAssets::WallVertical,
-            GameAssets::WallHorizontal,
-            GameAssets::WallTopLeft,
-            GameAssets::WallTopRight,
-            GameAssets::WallBottomLeft,
-            GameAssets::WallBottomRight,
-            GameAssets::Door,
-            GameAssets::Dot,
-            GameAssets::PowerPill,
+            GameAssets::EnemyEyes,
+            GameAssets::Maze,
         ];
         GAME_ASSETS.iter()
     }
 
-    const fn get_file(&self) -> &str {
+    /// Sprites the atlas is packed from, laid out in a single row
+    pub const fn get_files(&self) -> &[&str] {
         match self {
-            Self::Player => PLAYER,
-            Self::Blinkus => BLINKUS,
-            Self::WallVertical => WALLVERTICAL,
-            Self::WallHorizontal => WALLHORIZONTAL,
-            Self::WallTopLeft => WALLTOPLEFT,
-            Self::WallTopRight => WALLTOPRIGHT,
-            Self::WallBottomLeft => WALLBOTTOMLEFT,
-            Self::WallBottomRight => WALLBOTTOMRIGHT,
-            Self::Door => DOOR,
-            Self::Dot => DOT,
-            Self::PowerPill => POWERPILL,
+            Self::Player => &[PLAYER],
+            Self::Blinkus => &[BLINKUS],
+            Self::EnemyEyes => &[ENEMY_EYES],
+            Self::Maze => &MAZE_TILES,
         }
     }
 }
 
+/// Assets the game waits for before showing the title screen
 #[derive(Resource, Default)]
+pub struct LoadingQueue {
+    handles: Vec<UntypedHandle>,
+}
+
+impl LoadingQueue {
+    pub fn add(&mut self, handle: impl Into<UntypedHandle>) {
+        self.handles.push(handle.into());
+    }
+
+    /// Share of the assets either loaded or given up on, from 0 to 1
+    pub fn get_progress(&self, asset_server: &AssetServer) -> f32 {
+        if self.handles.is_empty() {
+            return 1.;
+        }
+        let settled = self
+            .handles
+            .iter()
+            .filter(|handle| is_settled(asset_server, handle.id()))
+            .count();
+        settled as f32 / self.handles.len() as f32
+    }
+}
+
+/// Whether the asset and everything it depends on stopped loading, whether
+/// it worked or not
+fn is_settled(asset_server: &AssetServer, id: UntypedAssetId) -> bool {
+    // Assets added in code have no load state
+    asset_server
+        .get_load_states(id)
+        .is_none_or(|(load_state, _, dependencies)| {
+            load_state == LoadState::Failed
+                || matches!(
+                    dependencies,
+                    RecursiveDependencyLoadState::Loaded | RecursiveDependencyLoadState::Failed
+                )
+        })
+}
+
+#[derive(Resource, Default, PartialEq, Eq)]
 pub struct GameAssetsLoader {
-    assets: HashMap<GameAssets, Handle<Image>>,
+    atlas: Handle<Image>,
+    manifest: Handle<AtlasManifest>,
 }
 
 impl GameAssetsLoader {
-    pub fn get(&self, asset: GameAssets) -> Handle<Image> {
-        if let Some(handle) = self.assets.get(&asset) {
-            return handle.clone();
-        }
-        panic!("Asset not found or not loaded: {}", asset.get_file());
+    pub const fn new(atlas: Handle<Image>, manifest: Handle<AtlasManifest>) -> Self {
+        Self { atlas, manifest }
+    }
+
+    /// Image every sprite is drawn from
+    pub fn get_atlas(&self) -> Handle<Image> {
+        self.atlas.clone()
+    }
+
+    /// Where the sprites are in the atlas, once loaded
+    pub fn get_manifest<'a>(
+        &self,
+        manifests: &'a Assets<AtlasManifest>,
+    ) -> Option<&'a AtlasManifest> {
+        manifests.get(&self.manifest)
     }
 }
 
 fn load_assets(
     mut commands: Commands,
     mut game_assets: ResMut<GameAssetsLoader>,
+    mut loading_queue: ResMut<LoadingQueue>,
     asset_server: Res<AssetServer>,
 ) {
-    for asset in GameAssets::iterator() {
-        let handle = asset_server.load(asset.get_file());
-        game_assets.assets.insert(*asset, handle);
-    }
+    game_assets.atlas = asset_server.load(ATLAS);
+    game_assets.manifest = asset_server.load(ATLAS_MANIFEST);
+    loading_queue.add(game_assets.atlas.clone());
+    loading_queue.add(game_assets.manifest.clone());
 
     let handle: Handle<Font> = asset_server.load(UI_FONT);
+    loading_queue.add(handle.clone());
     commands.insert_resource(UiFont { default: handle });
+
+    let animations = GameAnimations {
+        player: asset_server.load(PLAYER_ANIMATIONS),
+        enemy: asset_server.load(ENEMY_ANIMATIONS),
+        enemy_eyes: asset_server.load(ENEMY_EYES_ANIMATIONS),
+        effects: asset_server.load(EFFECTS_ANIMATIONS),
+    };
+    for handle in [
+        &animations.player,
+        &animations.enemy,
+        &animations.enemy_eyes,
+        &animations.effects,
+    ] {
+        loading_queue.add(handle.clone());
+    }
+    commands.insert_resource(animations);
+}
+
+/// Shows the title screen once every queued asset is loaded or has failed,
+/// the failed ones were replaced by placeholders already
+fn finish_loading(
+    mut loading_queue: ResMut<LoadingQueue>,
+    mut next_state: ResMut<NextState<GameState>>,
+    asset_server: Res<AssetServer>,
+) {
+    if loading_queue.get_progress(&asset_server) < 1. {
+        return;
+    }
+    // Later loads, like the assets of a theme, are not waited for
+    loading_queue.handles.clear();
+    next_state.set(GameState::Title);
+}
+
+/// Logs the assets of the given type that could not be loaded and puts the
+/// placeholder built from the other assets of the type in their place
+fn replace_failed<A: Asset>(
+    placeholder: fn(&Assets<A>) -> Option<A>,
+) -> impl FnMut(EventReader<AssetLoadFailedEvent<A>>, ResMut<Assets<A>>) {
+    move |mut event_reader: EventReader<AssetLoadFailedEvent<A>>, mut assets: ResMut<Assets<A>>| {
+        for event in event_reader.read() {
+            if let Some(asset) = placeholder(&assets) {
+                error!("Unable to load {}, using a placeholder", event.path);
+                assets.insert(event.id, asset);
+            } else {
+                error!("Unable to load {}", event.path);
+            }
+        }
+    }
+}
+
+/// Magenta checkerboard, hard to miss
+fn build_placeholder_image() -> Image {
+    let mut data = Vec::new();
+    for y in 0..PLACEHOLDER_SIZE {
+        for x in 0..PLACEHOLDER_SIZE {
+            let square = (x / PLACEHOLDER_SQUARE + y / PLACEHOLDER_SQUARE) % 2;
+            data.extend(PLACEHOLDER_COLORS[square as usize]);
+        }
+    }
+    Image::new(
+        Extent3d {
+            width: PLACEHOLDER_SIZE,
+            height: PLACEHOLDER_SIZE,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::default(),
+    )
+}
+
+/// Draws the sprites from the new atlas once a theme replaces it
+fn update_sprite_atlas(
+    mut query: Query<&mut Handle<Image>, With<TextureAtlas>>,
+    game_assets: Res<GameAssetsLoader>,
+) {
+    for mut texture in &mut query {
+        if *texture != game_assets.atlas {
+            *texture = game_assets.get_atlas();
+        }
+    }
 }
@@ -1,48 +1,518 @@
-use crate::state::{GameEvent, GameGlobals};
-use bevy::prelude::*;
+use crate::{
+    movement::Directions,
+    state::{GameEvent, GameGlobals},
+    storage, MAX_PLAYERS,
+};
+use bevy::{
+    input::gamepad::{GamepadConnection, GamepadConnectionEvent},
+    prelude::*,
+    utils::HashMap,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const KEY_MAP_STORAGE: &str = "controls";
+/// Analog stick values below this are ignored
+const STICK_DEADZONE: f32 = 0.5;
+const MOVE_ACTIONS: [(Action, Directions); 4] = [
+    (Action::MoveUp, Directions::Up),
+    (Action::MoveDown, Directions::Down),
+    (Action::MoveLeft, Directions::Left),
+    (Action::MoveRight, Directions::Right),
+];
+/// Lets a second player share the keyboard, these always drive the second
+/// player whatever device they come from
+const SECOND_PLAYER_ACTIONS: [(Action, Directions); 4] = [
+    (Action::SecondUp, Directions::Up),
+    (Action::SecondDown, Directions::Down),
+    (Action::SecondLeft, Directions::Left),
+    (Action::SecondRight, Directions::Right),
+];
 
 pub struct InputPlugin;
 
 impl Plugin for InputPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, read_inputs)
+        let key_map =
+            storage::load(KEY_MAP_STORAGE).map_or_else(KeyMap::default, |mut key_map: KeyMap| {
+                key_map.add_missing_defaults();
+                key_map
+            });
+        app.insert_resource::<KeyMap>(key_map)
+            .init_resource::<GamepadSlots>()
+            .add_systems(
+                Update,
+                (
+                    assign_gamepads.run_if(on_event::<GamepadConnectionEvent>()),
+                    read_inputs,
+                    read_gamepad_sticks,
+                    read_menu_inputs,
+                    save_key_map
+                        .run_if(resource_changed::<KeyMap>.and_then(not(resource_added::<KeyMap>))),
+                ),
+            )
             .add_event::<InputDirectionEvent>()
+            .add_event::<MenuInputEvent>()
             .add_event::<GameEvent>();
     }
 }
 
+/// Direction requested by one of the players. It is sent every frame for as
+/// long as the direction is held.
 #[derive(Event)]
-pub enum InputDirectionEvent {
+pub struct InputDirectionEvent {
+    pub player: usize,
+    pub direction: Directions,
+}
+
+/// Device a player input comes from. Gamepads drive the player of the slot
+/// they are assigned to, the keyboard drives the first player on the board
+/// without a gamepad.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InputSource {
+    Keyboard,
+    Gamepad(Gamepad),
+}
+
+/// Gamepads assigned to each player, in the order they were connected
+#[derive(Resource, Default)]
+pub struct GamepadSlots {
+    slots: [Option<Gamepad>; MAX_PLAYERS],
+}
+
+impl GamepadSlots {
+    pub fn get_player(&self, source: InputSource, players_on_board: usize) -> Option<usize> {
+        match source {
+            InputSource::Keyboard => Some(
+                self.slots
+                    .iter()
+                    .take(players_on_board)
+                    .position(Option::is_none)
+                    .unwrap_or(0),
+            ),
+            InputSource::Gamepad(gamepad) => {
+                self.slots.iter().position(|slot| *slot == Some(gamepad))
+            }
+        }
+    }
+
+    fn get_sources(&self) -> impl Iterator<Item = InputSource> + '_ {
+        std::iter::once(InputSource::Keyboard).chain(
+            self.slots
+                .iter()
+                .flatten()
+                .map(|gamepad| InputSource::Gamepad(*gamepad)),
+        )
+    }
+}
+
+/// Navigation through the title screen and the menus
+#[derive(Event, Clone, Copy, PartialEq, Eq)]
+pub enum MenuInputEvent {
     Up,
     Down,
-    Left,
-    Right,
+    Confirm,
+    Back,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Action {
+    MoveUp,
+    MoveDown,
+    MoveLeft,
+    MoveRight,
+    SecondUp,
+    SecondDown,
+    SecondLeft,
+    SecondRight,
+    Pause,
+    ToggleGrid,
+    Debug,
+}
+
+impl Action {
+    pub fn iterator() -> std::slice::Iter<'static, Self> {
+        static ACTIONS: [Action; 11] = [
+            Action::MoveUp,
+            Action::MoveDown,
+            Action::MoveLeft,
+            Action::MoveRight,
+            Action::SecondUp,
+            Action::SecondDown,
+            Action::SecondLeft,
+            Action::SecondRight,
+            Action::Pause,
+            Action::ToggleGrid,
+            Action::Debug,
+        ];
+        ACTIONS.iter()
+    }
+
+    pub const fn get_name(self) -> &'static str {
+        match self {
+            Self::MoveUp => "UP",
+            Self::MoveDown => "DOWN",
+            Self::MoveLeft => "LEFT",
+            Self::MoveRight => "RIGHT",
+            Self::SecondUp => "P2 UP",
+            Self::SecondDown => "P2 DOWN",
+            Self::SecondLeft => "P2 LEFT",
+            Self::SecondRight => "P2 RIGHT",
+            Self::Pause => "PAUSE",
+            Self::ToggleGrid => "GRID",
+            Self::Debug => "DEBUG",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InputBinding {
+    Key(KeyCode),
+    GamepadButton(GamepadButtonType),
+}
+
+impl InputBinding {
+    pub fn get_name(self) -> String {
+        let name = match self {
+            Self::Key(key) => format!("{key:?}"),
+            Self::GamepadButton(button) => format!("PAD {button:?}"),
+        };
+        name.trim_start_matches("Key")
+            .trim_start_matches("Digit")
+            .to_uppercase()
+    }
+}
+
+/// Keys and buttons bound to every action, saved every time it changes
+#[derive(Resource, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyMap {
+    bindings: BTreeMap<Action, Vec<InputBinding>>,
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        use InputBinding::{GamepadButton, Key};
+        let bindings = BTreeMap::from([
+            (
+                Action::MoveUp,
+                vec![
+                    Key(KeyCode::KeyW),
+                    Key(KeyCode::ArrowUp),
+                    GamepadButton(GamepadButtonType::DPadUp),
+                ],
+            ),
+            (
+                Action::MoveDown,
+                vec![
+                    Key(KeyCode::KeyS),
+                    Key(KeyCode::ArrowDown),
+                    GamepadButton(GamepadButtonType::DPadDown),
+                ],
+            ),
+            (
+                Action::MoveLeft,
+                vec![
+                    Key(KeyCode::KeyA),
+                    Key(KeyCode::ArrowLeft),
+                    GamepadButton(GamepadButtonType::DPadLeft),
+                ],
+            ),
+            (
+                Action::MoveRight,
+                vec![
+                    Key(KeyCode::KeyD),
+                    Key(KeyCode::ArrowRight),
+                    GamepadButton(GamepadButtonType::DPadRight),
+                ],
+            ),
+            (Action::SecondUp, vec![Key(KeyCode::KeyI)]),
+            (Action::SecondDown, vec![Key(KeyCode::KeyK)]),
+            (Action::SecondLeft, vec![Key(KeyCode::KeyJ)]),
+            (Action::SecondRight, vec![Key(KeyCode::KeyL)]),
+            (
+                Action::Pause,
+                vec![Key(KeyCode::Space), GamepadButton(GamepadButtonType::Start)],
+            ),
+            (Action::ToggleGrid, vec![Key(KeyCode::KeyG)]),
+            (Action::Debug, vec![Key(KeyCode::F3)]),
+        ]);
+        Self { bindings }
+    }
+}
+
+impl KeyMap {
+    pub fn get_bindings(&self, action: Action) -> &[InputBinding] {
+        self.bindings.get(&action).map_or(&[], Vec::as_slice)
+    }
+
+    /// Actions added after the key map was saved get their default bindings
+    fn add_missing_defaults(&mut self) {
+        for (action, bindings) in Self::default().bindings {
+            self.bindings.entry(action).or_insert(bindings);
+        }
+    }
+
+    /// Adds the input to the bindings of the action, it is removed from any
+    /// other action that was using it
+    pub fn bind(&mut self, action: Action, binding: InputBinding) {
+        for bindings in self.bindings.values_mut() {
+            bindings.retain(|other| *other != binding);
+        }
+        self.bindings.entry(action).or_default().push(binding);
+    }
+
+    /// Leaves the action without any input, until something is bound again
+    pub fn clear(&mut self, action: Action) {
+        self.bindings.insert(action, Vec::new());
+    }
+
+    pub fn just_pressed(&self, action: Action, inputs: &Inputs) -> bool {
+        self.get_bindings(action)
+            .iter()
+            .any(|binding| inputs.just_pressed(*binding))
+    }
+
+    pub fn just_pressed_on(&self, action: Action, inputs: &Inputs, source: InputSource) -> bool {
+        self.get_bindings(action)
+            .iter()
+            .any(|binding| inputs.just_pressed_on(*binding, source))
+    }
+
+    pub fn pressed_on(&self, action: Action, inputs: &Inputs, source: InputSource) -> bool {
+        self.get_bindings(action)
+            .iter()
+            .any(|binding| inputs.pressed_on(*binding, source))
+    }
+}
+
+/// Raw keyboard and gamepad state, used to match the key map bindings
+#[derive(bevy::ecs::system::SystemParam)]
+pub struct Inputs<'w> {
+    keyboard: Res<'w, ButtonInput<KeyCode>>,
+    gamepads: Res<'w, Gamepads>,
+    gamepad_buttons: Res<'w, ButtonInput<GamepadButton>>,
+}
+
+impl Inputs<'_> {
+    pub fn just_pressed(&self, binding: InputBinding) -> bool {
+        match binding {
+            InputBinding::Key(key) => self.keyboard.just_pressed(key),
+            InputBinding::GamepadButton(button_type) => self.gamepads.iter().any(|gamepad| {
+                self.gamepad_buttons
+                    .just_pressed(GamepadButton::new(gamepad, button_type))
+            }),
+        }
+    }
+
+    pub fn just_pressed_on(&self, binding: InputBinding, source: InputSource) -> bool {
+        match (binding, source) {
+            (InputBinding::Key(key), InputSource::Keyboard) => self.keyboard.just_pressed(key),
+            (InputBinding::GamepadButton(button_type), InputSource::Gamepad(gamepad)) => self
+                .gamepad_buttons
+                .just_pressed(GamepadButton::new(gamepad, button_type)),
+            _ => false,
+        }
+    }
+
+    pub fn pressed_on(&self, binding: InputBinding, source: InputSource) -> bool {
+        match (binding, source) {
+            (InputBinding::Key(key), InputSource::Keyboard) => self.keyboard.pressed(key),
+            (InputBinding::GamepadButton(button_type), InputSource::Gamepad(gamepad)) => self
+                .gamepad_buttons
+                .pressed(GamepadButton::new(gamepad, button_type)),
+            _ => false,
+        }
+    }
+
+    /// First key or gamepad button pressed in this frame, if any
+    pub fn get_any_just_pressed(&self) -> Option<InputBinding> {
+        if let Some(key) = self.keyboard.get_just_pressed().next() {
+            return Some(InputBinding::Key(*key));
+        }
+        self.gamepad_buttons
+            .get_just_pressed()
+            .next()
+            .map(|button| InputBinding::GamepadButton(button.button_type))
+    }
 }
 
 fn read_inputs(
-    keyboard_input: Res<ButtonInput<KeyCode>>,
+    inputs: Inputs,
+    key_map: Res<KeyMap>,
+    gamepad_slots: Res<GamepadSlots>,
     mut direction_event_writer: EventWriter<InputDirectionEvent>,
     mut game_state_event_writer: EventWriter<GameEvent>,
     mut game_globals: ResMut<GameGlobals>,
+    mut held_directions: Local<HashMap<(InputSource, usize), Vec<Directions>>>,
 ) {
-    if keyboard_input.just_pressed(KeyCode::KeyA) || keyboard_input.just_pressed(KeyCode::ArrowLeft)
-    {
-        direction_event_writer.send(InputDirectionEvent::Left);
-    } else if keyboard_input.just_pressed(KeyCode::KeyD)
-        || keyboard_input.just_pressed(KeyCode::ArrowRight)
+    // Any key leaves the demo and goes back to the title screen
+    if game_globals.attract_mode {
+        if inputs.get_any_just_pressed().is_some() {
+            game_state_event_writer.send(GameEvent::ExitToTitle);
+        }
+        return;
+    }
+
+    // Every device keeps the directions held down in the order they were
+    // pressed. The latest one wins, and releasing it falls back to the one
+    // held before.
+    let players_on_board = game_globals.get_players_on_board();
+    held_directions.retain(|(source, _), _| {
+        gamepad_slots
+            .get_player(*source, players_on_board)
+            .is_some()
+    });
+    for source in gamepad_slots.get_sources() {
+        let Some(player) = gamepad_slots.get_player(source, players_on_board) else {
+            continue;
+        };
+        for (set, (player, actions)) in [(player, MOVE_ACTIONS), (1, SECOND_PLAYER_ACTIONS)]
+            .into_iter()
+            .enumerate()
+        {
+            let held = held_directions.entry((source, set)).or_default();
+            held.retain(|held_direction| {
+                actions.iter().any(|(action, direction)| {
+                    direction == held_direction && key_map.pressed_on(*action, &inputs, source)
+                })
+            });
+            for (action, direction) in actions {
+                if key_map.just_pressed_on(action, &inputs, source) {
+                    held.retain(|held_direction| *held_direction != direction);
+                    held.push(direction);
+                }
+            }
+            if let Some(&direction) = held.last() {
+                direction_event_writer.send(InputDirectionEvent { player, direction });
+            }
+        }
+    }
+
+    if key_map.just_pressed(Action::ToggleGrid, &inputs) {
+        game_globals.show_grid ^= true;
+    }
+    if key_map.just_pressed(Action::Debug, &inputs) {
+        game_globals.is_debug ^= true;
+    }
+    if key_map.just_pressed(Action::Pause, &inputs) {
+        game_state_event_writer.send(GameEvent::TogglePause);
+    }
+}
+
+/// Menus are always reachable with Enter/Escape and the gamepad face
+/// buttons, whatever the key map says
+fn read_menu_inputs(
+    inputs: Inputs,
+    key_map: Res<KeyMap>,
+    game_globals: Res<GameGlobals>,
+    mut menu_event_writer: EventWriter<MenuInputEvent>,
+) {
+    if game_globals.attract_mode {
+        return;
+    }
+
+    let confirm = [
+        InputBinding::Key(KeyCode::Enter),
+        InputBinding::Key(KeyCode::NumpadEnter),
+        InputBinding::GamepadButton(GamepadButtonType::South),
+    ];
+    let back = [
+        InputBinding::Key(KeyCode::Escape),
+        InputBinding::GamepadButton(GamepadButtonType::East),
+    ];
+
+    if confirm
+        .into_iter()
+        .any(|binding| inputs.just_pressed(binding))
     {
-        direction_event_writer.send(InputDirectionEvent::Right);
-    } else if keyboard_input.just_pressed(KeyCode::KeyW)
-        || keyboard_input.just_pressed(KeyCode::ArrowUp)
+        menu_event_writer.send(MenuInputEvent::Confirm);
+    } else if back.into_iter().any(|binding| inputs.just_pressed(binding)) {
+        menu_event_writer.send(MenuInputEvent::Back);
+    } else if key_map.just_pressed(Action::MoveUp, &inputs)
+        || inputs.just_pressed(InputBinding::Key(KeyCode::ArrowUp))
     {
-        direction_event_writer.send(InputDirectionEvent::Up);
-    } else if keyboard_input.just_pressed(KeyCode::KeyS)
-        || keyboard_input.just_pressed(KeyCode::ArrowDown)
+        menu_event_writer.send(MenuInputEvent::Up);
+    } else if key_map.just_pressed(Action::MoveDown, &inputs)
+        || inputs.just_pressed(InputBinding::Key(KeyCode::ArrowDown))
     {
-        direction_event_writer.send(InputDirectionEvent::Down);
-    } else if keyboard_input.just_pressed(KeyCode::KeyG) {
-        game_globals.show_grid ^= true;
-    } else if keyboard_input.just_pressed(KeyCode::Space) {
-        game_state_event_writer.send(GameEvent::TogglePause);
+        menu_event_writer.send(MenuInputEvent::Down);
+    }
+}
+
+fn save_key_map(key_map: Res<KeyMap>) {
+    storage::save(KEY_MAP_STORAGE, &*key_map);
+}
+
+/// Turns the left stick of every assigned gamepad into one of the four
+/// directions, sent for as long as the stick is pushed
+fn read_gamepad_sticks(
+    axes: Res<Axis<GamepadAxis>>,
+    gamepad_slots: Res<GamepadSlots>,
+    game_globals: Res<GameGlobals>,
+    mut direction_event_writer: EventWriter<InputDirectionEvent>,
+) {
+    if game_globals.attract_mode {
+        return;
+    }
+    for (player, slot) in gamepad_slots.slots.iter().enumerate() {
+        let direction = slot.and_then(|gamepad| {
+            let x = axes.get(GamepadAxis::new(gamepad, GamepadAxisType::LeftStickX))?;
+            let y = axes.get(GamepadAxis::new(gamepad, GamepadAxisType::LeftStickY))?;
+            quantize_stick(x, y)
+        });
+        if let Some(direction) = direction {
+            direction_event_writer.send(InputDirectionEvent { player, direction });
+        }
+    }
+}
+
+fn quantize_stick(x: f32, y: f32) -> Option<Directions> {
+    if x.abs().max(y.abs()) < STICK_DEADZONE {
+        return None;
+    }
+    // The axis pushed the most wins, the stick Y axis points up
+    if x.abs() > y.abs() {
+        Some(if x > 0. {
+            Directions::Right
+        } else {
+            Directions::Left
+        })
+    } else {
+        Some(if y > 0. {
+            Directions::Up
+        } else {
+            Directions::Down
+        })
+    }
+}
+
+/// Gives every newly connected gamepad the first free player slot and frees
+/// it again when the gamepad is unplugged
+fn assign_gamepads(
+    mut gamepad_slots: ResMut<GamepadSlots>,
+    mut event_reader: EventReader<GamepadConnectionEvent>,
+) {
+    for event in event_reader.read() {
+        match &event.connection {
+            GamepadConnection::Connected(info) => {
+                if gamepad_slots.slots.contains(&Some(event.gamepad)) {
+                    continue;
+                }
+                if let Some(slot) = gamepad_slots.slots.iter_mut().find(|slot| slot.is_none()) {
+                    *slot = Some(event.gamepad);
+                    info!("Gamepad {} connected: {}", event.gamepad.id, info.name);
+                } else {
+                    info!("Gamepad {} ignored, all the players have one", info.name);
+                }
+            }
+            GamepadConnection::Disconnected => {
+                for slot in &mut gamepad_slots.slots {
+                    if *slot == Some(event.gamepad) {
+                        *slot = None;
+                        info!("Gamepad {} disconnected", event.gamepad.id);
+                    }
+                }
+            }
+        }
     }
 }
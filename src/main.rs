@@ -14,18 +14,33 @@ clippy::nursery,
 #![allow(clippy::single_match)]
 #![allow(clippy::module_name_repetitions)]
 #![allow(clippy::needless_pass_by_value)]
+mod atlas;
+mod audio;
 mod board;
 mod camera;
 mod collision;
 mod debug;
+mod effects;
+#[cfg(feature = "embed_assets")]
+mod embedded;
 mod enemies;
 mod game_assets;
 mod input;
 mod movement;
+#[cfg(not(target_family = "wasm"))]
+mod net;
 mod pickup;
 mod player;
+mod score;
+mod simulation;
+#[cfg(not(target_family = "wasm"))]
+mod spectator;
 mod spritesheet;
 mod state;
+mod storage;
+mod theme;
+mod tilemap;
+mod touch;
 mod ui;
 
 use bevy::prelude::*;
@@ -41,8 +56,11 @@ use input::InputPlugin;
 use movement::Directions;
 use movement::MovementPlugin;
 use player::PlayerPlugin;
+use score::ScorePlugin;
+use simulation::SimulationPlugin;
 use spritesheet::AnimatedSpritePlugin;
 use state::StatePlugin;
+use touch::TouchPlugin;
 
 const FONT_SIZE: f32 = 20.0;
 const UI_HEIGHT: f32 = 50.;
@@ -51,21 +69,70 @@ const WINDOW_HEIGHT: f32 = 496.0;
 const CELL_SIZE: f32 = 16.;
 const BOARD_WIDTH: f32 = 28.;
 const BOARD_HEIGHT: f32 = 31.;
+/// Cells on each side of the square pieces the maze is drawn in
+const TILE_CHUNK_SIZE: usize = 8;
+/// Pixels left empty between the sprites packed in the atlas
+#[cfg(not(target_family = "wasm"))]
+const ATLAS_PADDING: u32 = 2;
 
 const STARTING_POSITION_X: f32 = 14.;
 const STARTING_POSITION_Y: f32 = 23.;
 const STARTING_DIRECTION: Directions = Directions::Left;
 const PLAYER_VELOCITY: f32 = 8.;
+/// How far from the centre of a crossing, in cells, a turn is still taken
+const TURN_WINDOW: f32 = 0.25;
+/// Seconds a turn that cannot be taken yet is remembered
+const TURN_BUFFER_TIME: f32 = 0.5;
 
+const MAX_PLAYERS: usize = 2;
 const INTIAL_LIVES: isize = 2;
+const ATTRACT_DELAY: f32 = 10.;
+const READY_TIME: f32 = 2.;
+const GAME_OVER_TIME: f32 = 3.;
+const EXTRA_LIFE_SCORE: usize = 10_000;
+const EXTRA_LIFE_REPEAT: Option<std::num::NonZeroUsize> = None;
 const POWERPILL_SCORE: usize = 50;
 const DOT_SCORE: usize = 10;
+const ENEMY_SCORE: usize = 200;
+/// Awarded to the player driving an enemy in versus games for every catch
+const CATCH_SCORE: usize = 1600;
+const ALL_ENEMIES_BONUS: usize = 12000;
 const PICKUP_RANGE: f32 = 0.5;
 const ENEMY_RANGE: f32 = 1.0;
+const FRIGHTENED_TIME: f32 = 6.;
+const FRIGHTENED_VELOCITY: f32 = 5.;
+/// Seconds before frightened enemies calm down during which they flash
+const FRIGHTENED_FLASH_TIME: f32 = 2.;
+/// Speed of eaten enemies running back home
+const EYES_VELOCITY: f32 = 15.;
+/// How close to their starting cell eaten enemies have to get to be back
+const EYES_RANGE: f32 = 0.5;
+/// Ticks a network game runs ahead of the last inputs of the other player
+const MAX_PREDICTION: u32 = 8;
+/// Ticks between two comparisons of the state of both network players
+const DESYNC_CHECK_INTERVAL: u32 = 60;
+/// Seconds without news from the other network player before giving up
+const NET_TIMEOUT: f32 = 5.;
+/// Speed of the siren once the board is almost cleared
+const MAX_SIREN_SPEED: f32 = 1.5;
+const VOLUME_STEP: f32 = 0.2;
 
 fn main() {
-    App::new()
-        .insert_resource(ClearColor(Color::BLACK))
+    #[cfg(not(target_family = "wasm"))]
+    if let Some(address) = spectator::get_watch_address() {
+        spectator::watch(&address);
+        return;
+    }
+    #[cfg(not(target_family = "wasm"))]
+    if atlas::is_pack_requested() {
+        atlas::pack();
+        return;
+    }
+
+    let mut app = App::new();
+    #[cfg(feature = "embed_assets")]
+    app.add_plugins(embedded::EmbeddedAssetsPlugin);
+    app.insert_resource(ClearColor(Color::BLACK))
         .add_plugins(
             DefaultPlugins
                 .set(
@@ -86,17 +153,27 @@ fn main() {
                 }),
         )
         .init_gizmo_group::<DebugGizmos>()
+        .add_plugins(SimulationPlugin)
         .add_plugins(CameraPlugin)
+        .add_plugins(atlas::AtlasPlugin)
         .add_plugins(AssetLoaderPlugin)
+        .add_plugins(theme::ThemePlugin)
         .add_plugins(PlayerPlugin)
         .add_plugins(EnemiesPlugin)
         .add_plugins(MovementPlugin)
         .add_plugins(AnimatedSpritePlugin)
         .add_plugins(BoardPlugin)
         .add_plugins(CollisionPlugin)
+        .add_plugins(ScorePlugin)
+        .add_plugins(effects::EffectsPlugin)
         .add_plugins(InputPlugin)
+        .add_plugins(TouchPlugin)
         .add_plugins(DebugPlugin)
         .add_plugins(StatePlugin)
         .add_plugins(ui::GameUiPlugin)
-        .run();
+        .add_plugins(audio::GameAudioPlugin);
+    #[cfg(not(target_family = "wasm"))]
+    app.add_plugins(net::NetPlugin)
+        .add_plugins(spectator::SpectatorPlugin);
+    app.run();
 }
@@ -2,22 +2,28 @@ use crate::{
     board::{Board, CellType},
     enemies::Enemy,
     player::{Player, PlayerState},
+    simulation::{GameTick, RollbackApp, TickSet},
     state::GameState,
     CELL_SIZE,
 };
 use bevy::prelude::*;
+use serde::Deserialize;
 
 pub struct MovementPlugin;
 
 impl Plugin for MovementPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(
-            Update,
-            (
-                update_player_position.run_if(in_state(GameState::Running)),
-                update_enemy_position.run_if(in_state(GameState::Running)),
-            ),
-        );
+        app.rollback_component::<Position>()
+            .rollback_component::<Direction>()
+            .rollback_component::<Velocity>()
+            .rollback_component::<CorneringOffset>()
+            .rollback_component::<Transform>()
+            .add_systems(
+                GameTick,
+                (update_player_position, update_enemy_position)
+                    .in_set(TickSet::Movement)
+                    .run_if(in_state(GameState::Playing)),
+            );
     }
 }
 
@@ -30,9 +36,20 @@ pub struct MovingObjectBundle {
 
 pub trait MovableObject {
     fn update_direction(&self, pos: &Position, dir: &mut Direction, board: &Board);
+
+    /// Distance in cells from the centre of a crossing within which a turn
+    /// can still be taken. Zero means turns only happen on the centre.
+    fn get_turn_window(&self) -> f32 {
+        0.
+    }
 }
 
-#[derive(Component)]
+/// Visual offset left after cutting a corner. The position jumps onto the new
+/// lane straight away while the sprite slides there diagonally.
+#[derive(Component, Clone, Default)]
+pub struct CorneringOffset(Vec2);
+
+#[derive(Component, Clone)]
 pub struct Velocity {
     value: f32,
 }
@@ -43,7 +60,7 @@ impl Velocity {
     }
 }
 
-#[derive(PartialEq, Eq, Clone, Copy)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Deserialize)]
 pub enum Directions {
     Up,
     Down,
@@ -61,9 +78,18 @@ impl Directions {
         ];
         DIRECTIONS.iter()
     }
+
+    pub const fn opposite(self) -> Self {
+        match self {
+            Self::Up => Self::Down,
+            Self::Down => Self::Up,
+            Self::Left => Self::Right,
+            Self::Right => Self::Left,
+        }
+    }
 }
 
-#[derive(Component, PartialEq, Eq)]
+#[derive(Component, Clone, PartialEq, Eq)]
 pub struct Direction {
     pub current: Directions,
     pub next: Directions,
@@ -85,7 +111,7 @@ impl Direction {
     }
 }
 
-#[derive(Component, PartialEq)]
+#[derive(Component, Clone, PartialEq)]
 pub struct Position {
     pub x: f32,
     pub y: f32,
@@ -116,7 +142,7 @@ impl Position {
             Directions::Down => dest_y = dest_y.floor() + 1.,
             Directions::Left => dest_x = dest_x.ceil() - 1.,
             Directions::Right => dest_x = dest_x.floor() + 1.,
-        };
+        }
         Self {
             x: dest_x,
             y: dest_y,
@@ -138,7 +164,7 @@ impl Position {
             || (self.y.floor() - self.y).abs() > f32::EPSILON)
     }
 
-    pub fn get_cell_coords(&self) -> Self {
+    pub const fn get_cell_coords(&self) -> Self {
         Self::new(self.x.abs(), self.y.abs())
     }
 
@@ -159,23 +185,33 @@ fn update_player_position(
         &mut Direction,
         &mut Position,
         &mut Transform,
+        &mut CorneringOffset,
+        &mut PlayerState,
         &Player,
     )>,
     time: Res<Time>,
     board: Res<Board>,
-    mut next_state: ResMut<NextState<PlayerState>>,
 ) {
-    let (velocity, mut direction, mut position, mut transform, player) = query.single_mut();
-    let start_pos = Position::new(position.x, position.y);
+    for player in &mut query {
+        let (velocity, mut direction, mut position, mut transform, mut offset, mut state, player) =
+            player;
+        let start_pos = Position::new(position.x, position.y);
 
-    let distance = velocity.value * time.delta_seconds();
-    move_object(&mut direction, &mut position, &board, distance, player);
-    position.write_into(&mut transform);
+        let distance = velocity.value * time.delta_seconds();
+        offset.0 += move_object(&mut direction, &mut position, &board, distance, player);
+        // The sprite catches up with the lane at the same speed it moves
+        offset.0 = offset
+            .0
+            .clamp_length_max((offset.0.length() - distance).max(0.));
+        position.write_into(&mut transform);
+        transform.translation.x += offset.0.x * CELL_SIZE;
+        transform.translation.y -= offset.0.y * CELL_SIZE;
 
-    if start_pos == *position {
-        next_state.set(PlayerState::Idle);
-    } else {
-        next_state.set(PlayerState::Moving);
+        *state = if start_pos == *position {
+            PlayerState::Idle
+        } else {
+            PlayerState::Moving
+        };
     }
 }
 
@@ -199,15 +235,24 @@ fn update_enemy_position(
     }
 }
 
+/// Moves the object along the board and returns how far it was shifted
+/// sideways to cut a corner
+// A step either reaches the next cell or uses up the rest of the distance,
+// leaving exactly 0
+#[allow(clippy::while_float)]
 fn move_object(
     direction: &mut Direction,
     position: &mut Position,
     board: &Board,
     distance: f32,
     object: &dyn MovableObject,
-) {
+) -> Vec2 {
     let mut distance = distance;
+    let mut cornering_offset = Vec2::ZERO;
     while distance > 0. {
+        if !position.is_grid_aligned() {
+            cornering_offset += cut_corner(direction, position, board, object.get_turn_window());
+        }
         if position.is_grid_aligned() {
             object.update_direction(position, direction, board);
         }
@@ -218,6 +263,37 @@ fn move_object(
         distance = update_position(position, &dest, distance);
         wrap_around(position, board);
     }
+    cornering_offset
+}
+
+/// Takes a pending perpendicular turn a little before or after the centre of
+/// a crossing, as long as it is within the turn window. The object is put
+/// back on the centre of the crossing and the visual offset is returned.
+fn cut_corner(
+    direction: &mut Direction,
+    position: &mut Position,
+    board: &Board,
+    turn_window: f32,
+) -> Vec2 {
+    if direction.current == direction.next || direction.is_opposite(direction.next) {
+        return Vec2::ZERO;
+    }
+    let center = Position::new(position.x.round(), position.y.round());
+    let offset = Vec2::new(position.x - center.x, position.y - center.y);
+    if offset.length() > turn_window || board.get_cell(&center) == CellType::Outside {
+        return Vec2::ZERO;
+    }
+    let target = center.get_target_cell(direction.next);
+    if matches!(
+        board.get_cell(&target),
+        CellType::Wall(_) | CellType::Outside
+    ) {
+        return Vec2::ZERO;
+    }
+
+    *position = center;
+    direction.current = direction.next;
+    offset
 }
 
 fn wrap_around(position: &mut Position, board: &Board) {
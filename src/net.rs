@@ -0,0 +1,677 @@
+use crate::{
+    movement::{Direction, Directions, Position},
+    simulation::{
+        run_tick, LoadSnapshot, PendingInputs, Rollback, SaveSnapshot, SimRng, SimulationTick,
+        TickDriver, TickInputs,
+    },
+    state::{GameEvent, GameGlobals, GameMode, GameState},
+    DESYNC_CHECK_INTERVAL, MAX_PREDICTION, NET_TIMEOUT,
+};
+use bevy::{prelude::*, utils::HashMap};
+use rand::{rngs::StdRng, SeedableRng};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io::ErrorKind,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    time::Duration,
+};
+
+/// Seconds between two attempts to reach the host
+const HELLO_INTERVAL: f32 = 1.;
+/// Ticks of local inputs sent in every packet, so a lost packet is covered
+/// by the next ones
+const INPUT_WINDOW: usize = 32;
+
+/// Two players on two instances of the game, started with
+/// `--host PORT [--coop|--versus]` on one side and `--join ADDRESS` on the
+/// other one. Both sides run the simulation, only the inputs go through the
+/// network. Ticks whose remote input has not arrived yet are predicted and
+/// simulated again once it does.
+pub struct NetPlugin;
+
+impl Plugin for NetPlugin {
+    fn build(&self, app: &mut App) {
+        // Connecting while loading would start a game the title screen ends
+        app.add_systems(
+            PreUpdate,
+            poll_socket
+                .run_if(resource_exists::<NetSession>.and_then(not(in_state(GameState::Loading)))),
+        )
+        .add_systems(
+            OnTransition {
+                from: GameState::Ready,
+                to: GameState::Playing,
+            },
+            start_round.run_if(resource_exists::<NetSession>),
+        )
+        .add_systems(
+            OnEnter(GameState::Title),
+            end_session.run_if(resource_exists::<NetSession>),
+        )
+        .add_systems(
+            FixedUpdate,
+            advance_session.run_if(
+                resource_exists::<NetSession>
+                    .and_then(resource_equals(TickDriver::Network))
+                    .and_then(in_state(GameState::Playing)),
+            ),
+        );
+
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        let Some((role, address)) = parse_args(&args) else {
+            return;
+        };
+        let socket = match open_socket(address) {
+            Ok(socket) => socket,
+            Err(err) => {
+                error!("Unable to open a socket on {address}: {err}");
+                return;
+            }
+        };
+        if let Role::Host { .. } = role {
+            info!("Waiting for the other player on {address}");
+        }
+        app.insert_resource(NetSession::new(socket, role));
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Role {
+    /// Waits for the other player and picks the game mode
+    Host {
+        mode: GameMode,
+    },
+    Client {
+        server: SocketAddr,
+    },
+}
+
+#[derive(Resource)]
+struct NetSession {
+    socket: UdpSocket,
+    role: Role,
+    /// Seeds the simulation of both players, picked by the host
+    seed: u64,
+    /// The other player, once they answered
+    peer: Option<SocketAddr>,
+    /// Real time at which the other player was last heard of
+    last_heard: Duration,
+    last_hello: Option<Duration>,
+    /// Every round starts when the board is ready, ticks are numbered from
+    /// one within each round
+    round: u32,
+    /// Last tick simulated with the inputs of both players
+    confirmed_tick: u32,
+    /// Inputs of the local player in the current round, the first one is
+    /// for tick one
+    local_inputs: Vec<Option<Directions>>,
+    remote_inputs: HashMap<(u32, u32), Option<Directions>>,
+    /// The world holds predicted ticks, which are thrown away before
+    /// simulating again
+    predicting: bool,
+    local_hashes: HashMap<(u32, u32), u64>,
+    remote_hashes: HashMap<(u32, u32), u64>,
+    /// Hashes compared with the ones of the other player so far
+    checked_hashes: u32,
+    /// Compared hashes that did not match
+    desyncs: u32,
+}
+
+impl NetSession {
+    fn new(socket: UdpSocket, role: Role) -> Self {
+        Self {
+            socket,
+            role,
+            seed: rand::random(),
+            peer: None,
+            last_heard: Duration::ZERO,
+            last_hello: None,
+            round: 0,
+            confirmed_tick: 0,
+            local_inputs: Vec::new(),
+            remote_inputs: HashMap::new(),
+            predicting: false,
+            local_hashes: HashMap::new(),
+            remote_hashes: HashMap::new(),
+            checked_hashes: 0,
+            desyncs: 0,
+        }
+    }
+
+    /// The host plays as the first player and the client as the second one
+    const fn get_seat(&self) -> usize {
+        match self.role {
+            Role::Host { .. } => 0,
+            Role::Client { .. } => 1,
+        }
+    }
+
+    const fn get_local_tick(&self) -> u32 {
+        self.local_inputs.len() as u32
+    }
+
+    fn get_inputs(&self, tick: u32, remote: Option<Directions>) -> TickInputs {
+        let seat = self.get_seat();
+        let mut inputs = TickInputs::default();
+        inputs.directions[seat] = self.local_inputs[tick as usize - 1];
+        inputs.directions[1 - seat] = remote;
+        inputs
+    }
+
+    fn send_to(&self, message: &Message, address: SocketAddr) {
+        if let Err(err) = self.socket.send_to(&message.encode(), address) {
+            error!("Unable to send to {address}: {err}");
+        }
+    }
+
+    fn send(&self, message: &Message) {
+        if let Some(peer) = self.peer {
+            self.send_to(message, peer);
+        }
+    }
+
+    fn send_inputs(&self) {
+        let first = self.local_inputs.len().saturating_sub(INPUT_WINDOW);
+        if first == self.local_inputs.len() {
+            return;
+        }
+        self.send(&Message::Inputs {
+            round: self.round,
+            first_tick: first as u32 + 1,
+            inputs: self.local_inputs[first..].to_vec(),
+        });
+    }
+
+    fn check_hashes(&mut self) {
+        let (mut checked, mut desyncs) = (0, 0);
+        let local_hashes = &mut self.local_hashes;
+        self.remote_hashes.retain(|key, remote| {
+            let Some(local) = local_hashes.remove(key) else {
+                return true;
+            };
+            checked += 1;
+            if local != *remote {
+                error!("Desync detected in round {} at tick {}", key.0, key.1);
+                desyncs += 1;
+            }
+            false
+        });
+        self.checked_hashes += checked;
+        self.desyncs += desyncs;
+    }
+}
+
+enum Message {
+    Hello,
+    Welcome {
+        mode: GameMode,
+        seed: u64,
+    },
+    Inputs {
+        round: u32,
+        first_tick: u32,
+        inputs: Vec<Option<Directions>>,
+    },
+    Hash {
+        round: u32,
+        tick: u32,
+        hash: u64,
+    },
+}
+
+impl Message {
+    fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        match self {
+            Self::Hello => data.push(0),
+            Self::Welcome { mode, seed } => {
+                data.push(1);
+                data.push(match mode {
+                    GameMode::Alternating => 0,
+                    GameMode::Coop => 1,
+                    GameMode::Versus => 2,
+                });
+                data.extend(seed.to_le_bytes());
+            }
+            Self::Inputs {
+                round,
+                first_tick,
+                inputs,
+            } => {
+                data.push(2);
+                data.extend(round.to_le_bytes());
+                data.extend(first_tick.to_le_bytes());
+                data.extend(inputs.iter().map(|input| match input {
+                    None => 0,
+                    Some(Directions::Up) => 1,
+                    Some(Directions::Down) => 2,
+                    Some(Directions::Left) => 3,
+                    Some(Directions::Right) => 4,
+                }));
+            }
+            Self::Hash { round, tick, hash } => {
+                data.push(3);
+                data.extend(round.to_le_bytes());
+                data.extend(tick.to_le_bytes());
+                data.extend(hash.to_le_bytes());
+            }
+        }
+        data
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let (&tag, body) = data.split_first()?;
+        let read_u32 = |offset: usize| {
+            let bytes = body.get(offset..offset + 4)?;
+            Some(u32::from_le_bytes(bytes.try_into().ok()?))
+        };
+        let read_u64 = |offset: usize| {
+            let bytes = body.get(offset..offset + 8)?;
+            Some(u64::from_le_bytes(bytes.try_into().ok()?))
+        };
+        match tag {
+            0 => Some(Self::Hello),
+            1 => Some(Self::Welcome {
+                mode: match body.first()? {
+                    0 => GameMode::Alternating,
+                    1 => GameMode::Coop,
+                    2 => GameMode::Versus,
+                    _ => return None,
+                },
+                seed: read_u64(1)?,
+            }),
+            2 => Some(Self::Inputs {
+                round: read_u32(0)?,
+                first_tick: read_u32(4)?,
+                inputs: body
+                    .get(8..)?
+                    .iter()
+                    .map(|byte| match byte {
+                        1 => Some(Directions::Up),
+                        2 => Some(Directions::Down),
+                        3 => Some(Directions::Left),
+                        4 => Some(Directions::Right),
+                        _ => None,
+                    })
+                    .collect(),
+            }),
+            3 => Some(Self::Hash {
+                round: read_u32(0)?,
+                tick: read_u32(4)?,
+                hash: read_u64(8)?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Reads `--host PORT [--coop|--versus]` or `--join ADDRESS`, returns the
+/// role and the address to listen on
+fn parse_args(args: &[String]) -> Option<(Role, SocketAddr)> {
+    let get_value = |flag: &str| {
+        let index = args.iter().position(|arg| arg == flag)?;
+        args.get(index + 1)
+    };
+
+    if let Some(port) = get_value("--host") {
+        let Ok(port) = port.parse::<u16>() else {
+            error!("Invalid port {port}");
+            return None;
+        };
+        let mode = if args.iter().any(|arg| arg == "--versus") {
+            GameMode::Versus
+        } else {
+            GameMode::Coop
+        };
+        return Some((Role::Host { mode }, SocketAddr::from(([0, 0, 0, 0], port))));
+    }
+
+    let server = get_value("--join")?;
+    // The socket is bound to an IPv4 address, `localhost` may also resolve
+    // to an IPv6 one
+    let Some(server) = server
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addresses| addresses.find(SocketAddr::is_ipv4))
+    else {
+        error!("Invalid address {server}");
+        return None;
+    };
+    Some((Role::Client { server }, SocketAddr::from(([0, 0, 0, 0], 0))))
+}
+
+fn open_socket(address: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(address)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Both sides start a new game with the same seed once they know each other
+fn connect(
+    session: &mut NetSession,
+    peer: SocketAddr,
+    mode: GameMode,
+    rng: &mut SimRng,
+    driver: &mut TickDriver,
+    game_event_writer: &mut EventWriter<GameEvent>,
+) {
+    info!("Playing with {peer}");
+    session.peer = Some(peer);
+    session.round = 0;
+    rng.0 = StdRng::seed_from_u64(session.seed);
+    *driver = TickDriver::Network;
+    game_event_writer.send(GameEvent::NewGame {
+        attract_mode: false,
+        players: 2,
+        mode,
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+fn poll_socket(
+    mut commands: Commands,
+    mut session: ResMut<NetSession>,
+    mut rng: ResMut<SimRng>,
+    mut driver: ResMut<TickDriver>,
+    mut game_event_writer: EventWriter<GameEvent>,
+    time: Res<Time<Real>>,
+) {
+    let now = time.elapsed();
+    if let Role::Client { server } = session.role {
+        let hello_due = session.last_hello.is_none_or(|last_hello| {
+            now.saturating_sub(last_hello).as_secs_f32() >= HELLO_INTERVAL
+        });
+        if session.peer.is_none() && hello_due {
+            session.send_to(&Message::Hello, server);
+            session.last_hello = Some(now);
+        }
+    }
+
+    let mut buffer = [0; 512];
+    loop {
+        let (size, from) = match session.socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+            Err(err) => {
+                error!("Unable to receive: {err}");
+                break;
+            }
+        };
+        if session.peer.is_some_and(|peer| peer != from) {
+            continue;
+        }
+        let Some(message) = Message::decode(&buffer[..size]) else {
+            continue;
+        };
+
+        match (message, session.role) {
+            (Message::Hello, Role::Host { mode }) => {
+                if session.peer.is_none() {
+                    connect(
+                        &mut session,
+                        from,
+                        mode,
+                        &mut rng,
+                        &mut driver,
+                        &mut game_event_writer,
+                    );
+                }
+                // Sent again every time, in case the previous one was lost
+                let seed = session.seed;
+                session.send_to(&Message::Welcome { mode, seed }, from);
+            }
+            (Message::Welcome { mode, seed }, Role::Client { .. }) => {
+                if session.peer.is_none() {
+                    session.seed = seed;
+                    connect(
+                        &mut session,
+                        from,
+                        mode,
+                        &mut rng,
+                        &mut driver,
+                        &mut game_event_writer,
+                    );
+                }
+            }
+            (
+                Message::Inputs {
+                    round,
+                    first_tick,
+                    inputs,
+                },
+                _,
+            ) => {
+                if round >= session.round {
+                    for (tick, input) in (first_tick..).zip(inputs) {
+                        session.remote_inputs.insert((round, tick), input);
+                    }
+                }
+            }
+            (Message::Hash { round, tick, hash }, _) => {
+                session.remote_hashes.insert((round, tick), hash);
+            }
+            _ => continue,
+        }
+        session.last_heard = now;
+    }
+    session.check_hashes();
+
+    if session.peer.is_some() && now.saturating_sub(session.last_heard).as_secs_f32() > NET_TIMEOUT
+    {
+        error!("Lost the connection to the other player");
+        commands.remove_resource::<NetSession>();
+        *driver = TickDriver::Local;
+        game_event_writer.send(GameEvent::ExitToTitle);
+    }
+}
+
+/// The board was reset on both sides, the ticks start over
+fn start_round(mut session: ResMut<NetSession>) {
+    if session.peer.is_none() {
+        return;
+    }
+    session.round += 1;
+    session.confirmed_tick = 0;
+    session.local_inputs.clear();
+    session.predicting = false;
+    let round = session.round;
+    session
+        .remote_inputs
+        .retain(|(other, _), _| *other >= round);
+    session.local_hashes.clear();
+    session
+        .remote_hashes
+        .retain(|(other, _), _| *other >= round);
+}
+
+fn end_session(mut commands: Commands, session: Res<NetSession>, mut driver: ResMut<TickDriver>) {
+    if session.peer.is_none() {
+        return;
+    }
+    info!("Network game over");
+    commands.remove_resource::<NetSession>();
+    *driver = TickDriver::Local;
+}
+
+fn advance_session(world: &mut World) {
+    world.resource_scope(|world, mut session: Mut<NetSession>| {
+        if session.round == 0 || world.resource::<SimulationTick>().halted {
+            return;
+        }
+
+        // Waits for the other player when too far ahead
+        if session.get_local_tick() < session.confirmed_tick + MAX_PREDICTION {
+            let pending = std::mem::take(&mut world.resource_mut::<PendingInputs>().0);
+            let input = pending.directions.into_iter().flatten().next();
+            session.local_inputs.push(input);
+        }
+        session.send_inputs();
+
+        if session.predicting {
+            world.run_schedule(LoadSnapshot);
+            session.predicting = false;
+        }
+        let round = session.round;
+        while session.confirmed_tick < session.get_local_tick() {
+            let tick = session.confirmed_tick + 1;
+            let Some(&remote) = session.remote_inputs.get(&(round, tick)) else {
+                break;
+            };
+            run_tick(world, session.get_inputs(tick, remote), false);
+            session.confirmed_tick = tick;
+
+            if tick.is_multiple_of(DESYNC_CHECK_INTERVAL) {
+                let hash = get_state_hash(world);
+                session.local_hashes.insert((round, tick), hash);
+                session.send(&Message::Hash { round, tick, hash });
+            }
+            if world.resource::<SimulationTick>().halted {
+                break;
+            }
+        }
+        world.run_schedule(SaveSnapshot);
+        if world.resource::<SimulationTick>().halted {
+            return;
+        }
+
+        // The other player is expected to keep doing what they did last
+        let remote = session
+            .remote_inputs
+            .get(&(round, session.confirmed_tick))
+            .copied()
+            .flatten();
+        for tick in session.confirmed_tick + 1..=session.get_local_tick() {
+            run_tick(world, session.get_inputs(tick, remote), true);
+            session.predicting = true;
+        }
+    });
+}
+
+/// Hash of what both sides must agree on, entities are sorted as they are
+/// not stored in the same order on both sides
+fn get_state_hash(world: &mut World) -> u64 {
+    let mut query = world.query_filtered::<(&Position, &Direction), With<Rollback>>();
+    let mut objects: Vec<_> = query
+        .iter(world)
+        .map(|(position, direction)| {
+            (
+                position.x.to_bits(),
+                position.y.to_bits(),
+                direction.current as u8,
+                direction.next as u8,
+            )
+        })
+        .collect();
+    objects.sort_unstable();
+
+    let globals = world.resource::<GameGlobals>();
+    let mut hasher = DefaultHasher::new();
+    for object in objects {
+        object.hash(&mut hasher);
+    }
+    globals.current_player.hash(&mut hasher);
+    for progress in &globals.players {
+        (progress.score, progress.lives, progress.level).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        atlas::AtlasPlugin, board::BoardPlugin, collision::CollisionPlugin, enemies::EnemiesPlugin,
+        game_assets::AssetLoaderPlugin, input::InputPlugin, movement::MovementPlugin,
+        player::PlayerPlugin, score::ScorePlugin, simulation::SimulationPlugin,
+        spritesheet::AnimatedSpritePlugin, state::StatePlugin, theme::ThemePlugin,
+    };
+    use bevy::time::TimeUpdateStrategy;
+
+    /// Frames the test gives up after, a minute of game time
+    const MAX_FRAMES: usize = 64 * 60;
+    const CHECKS: u32 = 3;
+
+    /// The game without a window, a renderer or a sound device, with the
+    /// time moving one fixed step per frame
+    fn build_headless_app(session: NetSession) -> App {
+        let mut app = App::new();
+        app.add_plugins((
+            MinimalPlugins,
+            AssetPlugin::default(),
+            bevy::input::InputPlugin,
+        ))
+        .init_asset::<Image>()
+        .init_asset::<Mesh>()
+        .init_asset::<ColorMaterial>()
+        .init_asset::<TextureAtlasLayout>()
+        .init_asset::<Font>()
+        .insert_resource(TimeUpdateStrategy::ManualDuration(
+            Time::<Fixed>::default().timestep(),
+        ))
+        .add_plugins((
+            SimulationPlugin,
+            AtlasPlugin,
+            AssetLoaderPlugin,
+            ThemePlugin,
+            PlayerPlugin,
+            EnemiesPlugin,
+            MovementPlugin,
+            AnimatedSpritePlugin,
+            BoardPlugin,
+            CollisionPlugin,
+            ScorePlugin,
+            InputPlugin,
+            StatePlugin,
+            NetPlugin,
+        ))
+        .insert_resource(session);
+        app
+    }
+
+    #[test]
+    fn loopback_session_stays_in_sync() {
+        let localhost = SocketAddr::from(([127, 0, 0, 1], 0));
+        let host_socket = open_socket(localhost).unwrap();
+        let server = host_socket.local_addr().unwrap();
+        let host_session = NetSession::new(
+            host_socket,
+            Role::Host {
+                mode: GameMode::Coop,
+            },
+        );
+        let client_session =
+            NetSession::new(open_socket(localhost).unwrap(), Role::Client { server });
+        let mut apps = [
+            build_headless_app(host_session),
+            build_headless_app(client_session),
+        ];
+
+        // Each player turns every second, never the same way as the other
+        let directions = [
+            Directions::Left,
+            Directions::Up,
+            Directions::Right,
+            Directions::Down,
+        ];
+        for frame in 0..MAX_FRAMES {
+            for (seat, app) in apps.iter_mut().enumerate() {
+                let direction = directions[(frame / 64 + seat) % directions.len()];
+                app.world.resource_mut::<PendingInputs>().0.directions[seat] = Some(direction);
+                app.update();
+            }
+            let checked = apps
+                .iter()
+                .all(|app| app.world.resource::<NetSession>().checked_hashes >= CHECKS);
+            if checked {
+                break;
+            }
+        }
+
+        for app in &apps {
+            let session = app.world.resource::<NetSession>();
+            assert!(session.checked_hashes >= CHECKS);
+            assert_eq!(session.desyncs, 0);
+        }
+    }
+}
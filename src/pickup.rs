@@ -1,6 +1,7 @@
-use bevy::ecs::component::Component;
+use crate::movement::Position;
+use bevy::ecs::{component::Component, event::Event};
 
-#[derive(Component)]
+#[derive(Component, Clone)]
 pub struct Pickup {
     value: usize,
 }
@@ -19,3 +20,19 @@ pub struct Dot;
 
 #[derive(Component)]
 pub struct PowerPill;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PickupKind {
+    Dot,
+    PowerPill,
+}
+
+/// Sent every time a player collects something from the board
+#[derive(Event)]
+pub struct PickupEvent {
+    pub player: usize,
+    pub value: usize,
+    pub kind: PickupKind,
+    /// Where the pickup was on the board
+    pub position: Position,
+}
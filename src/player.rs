@@ -1,36 +1,87 @@
 use crate::board::CellType;
-use crate::game_assets::GameAssets;
+use crate::game_assets::GameAnimations;
 use crate::game_assets::GameAssetsLoader;
-use crate::input::InputDirectionEvent;
+use crate::movement::CorneringOffset;
 use crate::movement::Direction;
 use crate::movement::Directions;
 use crate::movement::MovableObject;
 use crate::movement::MovingObjectBundle;
 use crate::movement::Position;
 use crate::movement::Velocity;
+use crate::simulation::GameTick;
+use crate::simulation::Rollback;
+use crate::simulation::RollbackApp;
+use crate::simulation::SimRng;
+use crate::simulation::TickInputs;
+use crate::simulation::TickSet;
 use crate::spritesheet::AnimatedSpriteBundle;
-use crate::spritesheet::AnimationStrategy;
+use crate::spritesheet::AnimationState;
 use crate::spritesheet::SpriteSheetAnimator;
+use crate::state::GameEvent;
+use crate::state::GameGlobals;
+use crate::state::GameMode;
+use crate::state::GameState;
+use crate::MAX_PLAYERS;
 use crate::PLAYER_VELOCITY;
 use crate::STARTING_DIRECTION;
 use crate::STARTING_POSITION_X;
 use crate::STARTING_POSITION_Y;
+use crate::TURN_BUFFER_TIME;
+use crate::TURN_WINDOW;
 use bevy::prelude::*;
+use rand::seq::SliceRandom;
 
 pub struct PlayerPlugin;
 
 impl Plugin for PlayerPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, spawn_player)
-            .add_systems(Update, player_movement_control)
-            .init_state::<PlayerState>();
+        app.rollback_component::<TurnBuffer>()
+            .rollback_component::<PlayerState>()
+            .add_systems(OnExit(GameState::Loading), spawn_first_player)
+            .add_systems(OnEnter(GameState::Ready), reset_players)
+            .add_systems(
+                Update,
+                (
+                    spawn_players.run_if(on_event::<GameEvent>()),
+                    update_player_animation,
+                ),
+            )
+            .add_systems(
+                GameTick,
+                (
+                    player_movement_control.run_if(not(is_attract_mode).and_then(
+                        in_state(GameState::Ready).or_else(in_state(GameState::Playing)),
+                    )),
+                    attract_mode_control
+                        .run_if(is_attract_mode.and_then(in_state(GameState::Playing))),
+                    expire_turn_buffer.run_if(in_state(GameState::Playing)),
+                )
+                    .chain()
+                    .in_set(TickSet::Input),
+            );
     }
 }
 
+/// Tint of each player sprite, so players sharing the board can tell
+/// themselves apart
+const PLAYER_COLORS: [Color; MAX_PLAYERS] = [Color::WHITE, Color::rgb(0.4, 1.0, 0.6)];
+/// Cells between the starting positions of players sharing the board
+const PLAYER_SPACING: f32 = 3.;
+
 #[derive(Component)]
-pub struct Player;
+pub struct Player {
+    /// Index of the player on the board, the same one the input events use
+    pub index: usize,
+}
 
-#[derive(States, Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// A requested turn that cannot be taken yet is forgotten when this runs out,
+/// so a stale input does not cause a surprise turn later on
+#[derive(Component, Clone)]
+struct TurnBuffer(Timer);
+
+/// Whether the player walked during the last frame, the sprite only animates
+/// while it does
+#[derive(Component, Default, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PlayerState {
     #[default]
     Moving,
@@ -48,63 +99,126 @@ impl MovableObject for Player {
             dir.current = dir.next;
         }
     }
+
+    fn get_turn_window(&self) -> f32 {
+        TURN_WINDOW
+    }
 }
 
-fn spawn_player(
+/// Players sharing the board start next to each other, looking away from
+/// each other
+const fn get_start(index: usize) -> (Position, Directions) {
+    let position = Position::new(
+        (index as f32).mul_add(PLAYER_SPACING, STARTING_POSITION_X),
+        STARTING_POSITION_Y,
+    );
+    let direction = if index.is_multiple_of(2) {
+        STARTING_DIRECTION
+    } else {
+        STARTING_DIRECTION.opposite()
+    };
+    (position, direction)
+}
+
+fn spawn_first_player(
+    mut commands: Commands,
+    game_assets: Res<GameAssetsLoader>,
+    animations: Res<GameAnimations>,
+) {
+    spawn_player(&mut commands, &game_assets, &animations, 0);
+}
+
+/// Puts one player entity on the board for each player playing at the same
+/// time
+fn spawn_players(
     mut commands: Commands,
     game_assets: Res<GameAssetsLoader>,
-    mut texture_atlas_layouts: ResMut<Assets<TextureAtlasLayout>>,
+    animations: Res<GameAnimations>,
+    mut event_reader: EventReader<GameEvent>,
+    query: Query<Entity, With<Player>>,
 ) {
-    let position = Position::new(STARTING_POSITION_X, STARTING_POSITION_Y);
+    for event in event_reader.read() {
+        let GameEvent::NewGame { players, mode, .. } = event else {
+            continue;
+        };
+        for entity in query.iter() {
+            commands.entity(entity).despawn_recursive();
+        }
+        let count = match mode {
+            GameMode::Alternating | GameMode::Versus => 1,
+            GameMode::Coop => *players,
+        };
+        for index in 0..count {
+            spawn_player(&mut commands, &game_assets, &animations, index);
+        }
+    }
+}
+
+fn spawn_player(
+    commands: &mut Commands,
+    game_assets: &GameAssetsLoader,
+    animations: &GameAnimations,
+    index: usize,
+) {
+    let (position, direction) = get_start(index);
     let transform = Transform::from(&position);
-    let layout = TextureAtlasLayout::from_grid(Vec2::new(24., 24.), 3, 1, None, None);
-    let texture_atlas_layout = texture_atlas_layouts.add(layout);
 
     commands.spawn((
         MovingObjectBundle {
             position,
             velocity: Velocity::new(PLAYER_VELOCITY),
-            dir: Direction::new(STARTING_DIRECTION, STARTING_DIRECTION),
+            dir: Direction::new(direction, direction),
         },
         AnimatedSpriteBundle {
-            sprite_sheet_animator: SpriteSheetAnimator {
-                start: 0,
-                end: 2,
-                frame_rate: 10.,
-                strategy: AnimationStrategy::PingPong,
-                texture_atlas_layout: texture_atlas_layout.clone(),
-                ..Default::default()
-            },
+            sprite_sheet_animator: SpriteSheetAnimator::new(animations.player.clone()),
+            animation_state: AnimationState::Walk(direction),
             spritesheet_bundle: SpriteSheetBundle {
-                texture: game_assets.get(GameAssets::Player),
+                texture: game_assets.get_atlas(),
                 sprite: Sprite {
                     anchor: bevy::sprite::Anchor::Center,
+                    color: PLAYER_COLORS[index % MAX_PLAYERS],
                     ..Default::default()
                 },
-                atlas: TextureAtlas {
-                    layout: texture_atlas_layout,
-                    index: 0,
-                },
                 transform,
                 ..Default::default()
             },
         },
-        Player,
+        CorneringOffset::default(),
+        TurnBuffer(Timer::from_seconds(TURN_BUFFER_TIME, TimerMode::Once)),
+        PlayerState::default(),
+        Player { index },
+        Rollback,
     ));
 }
 
+/// Direction requested for the given player entity during this tick
+const fn get_steering(
+    game_globals: &GameGlobals,
+    inputs: TickInputs,
+    player: &Player,
+) -> Option<Directions> {
+    match game_globals.mode {
+        // Players take turns, the one playing steers with their own gamepad
+        // and the keyboard is shared by everybody
+        GameMode::Alternating => match inputs.directions[game_globals.current_player] {
+            Some(direction) => Some(direction),
+            None => inputs.directions[0],
+        },
+        GameMode::Coop | GameMode::Versus => inputs.directions[player.index],
+    }
+}
+
 fn player_movement_control(
-    mut query: Query<&mut Direction, With<Player>>,
-    mut input_event_reader: EventReader<InputDirectionEvent>,
+    mut query: Query<(&mut Direction, &mut TurnBuffer, &Player)>,
+    inputs: Res<TickInputs>,
+    game_globals: Res<GameGlobals>,
 ) {
-    let mut direction = query.single_mut();
-    for input_event in input_event_reader.read() {
-        match input_event {
-            InputDirectionEvent::Up => direction.next = Directions::Up,
-            InputDirectionEvent::Down => direction.next = Directions::Down,
-            InputDirectionEvent::Left => direction.next = Directions::Left,
-            InputDirectionEvent::Right => direction.next = Directions::Right,
-        }
+    for (mut direction, mut turn_buffer, player) in &mut query {
+        let Some(next) = get_steering(&game_globals, *inputs, player) else {
+            continue;
+        };
+        direction.next = next;
+        turn_buffer.0.reset();
 
         // If the next direction is opposite to the current one update the
         // direction immediately
@@ -119,3 +233,71 @@ fn player_movement_control(
         }
     }
 }
+
+fn is_attract_mode(game_globals: Res<GameGlobals>) -> bool {
+    game_globals.attract_mode
+}
+
+/// Drives the player around the board during the attract mode demo,
+/// choosing a random way at each crossing without ever turning back
+fn attract_mode_control(
+    mut query: Query<(&Position, &mut Direction), With<Player>>,
+    board: Res<crate::board::Board>,
+    mut rng: ResMut<SimRng>,
+) {
+    for (position, mut direction) in &mut query {
+        if !position.is_grid_aligned() || board.get_cell(position) == CellType::Outside {
+            continue;
+        }
+
+        let mut directions = board.get_neighbours(position.x, position.y);
+        directions.retain(|(_, cell)| !matches!(cell, CellType::Wall(_)));
+        directions.retain(|(new_dir, _)| !direction.is_opposite(*new_dir));
+        if let Some((next, _)) = directions.choose(&mut rng.0) {
+            direction.next = *next;
+        }
+    }
+}
+
+/// Puts the players back on their starting cells. Players sharing the board
+/// who ran out of lives leave it.
+fn reset_players(
+    mut commands: Commands,
+    mut query: Query<(Entity, &Player, &mut Position, &mut Direction)>,
+    game_globals: Res<GameGlobals>,
+) {
+    for (entity, player, mut position, mut direction) in &mut query {
+        if game_globals.get_progress(player.index).lives < 0 {
+            commands.entity(entity).despawn_recursive();
+            continue;
+        }
+        let (start_position, start_direction) = get_start(player.index);
+        *position = start_position;
+        *direction = Direction::new(start_direction, start_direction);
+    }
+}
+
+fn expire_turn_buffer(
+    mut query: Query<(&mut Direction, &mut TurnBuffer), With<Player>>,
+    time: Res<Time>,
+) {
+    for (mut direction, mut turn_buffer) in &mut query {
+        if turn_buffer.0.tick(time.delta()).just_finished() {
+            direction.next = direction.current;
+        }
+    }
+}
+
+fn update_player_animation(
+    mut query: Query<(&Direction, &PlayerState, &mut AnimationState), With<Player>>,
+    state: Res<State<GameState>>,
+) {
+    for (direction, player_state, mut animation_state) in &mut query {
+        let next = match (state.get(), player_state) {
+            (GameState::Dying, _) => AnimationState::Death,
+            (_, PlayerState::Idle) => AnimationState::Idle(direction.current),
+            (_, PlayerState::Moving) => AnimationState::Walk(direction.current),
+        };
+        animation_state.set_if_neq(next);
+    }
+}
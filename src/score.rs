@@ -0,0 +1,292 @@
+use crate::{
+    board::{Board, CellType},
+    effects::FloatingText,
+    enemies::{Enemy, EnemyKilledEvent, EnemyMode},
+    game_assets::{GameAnimations, UiFont},
+    movement::Position,
+    pickup::{PickupEvent, PickupKind},
+    simulation::{GameTick, RollbackApp, SimulationTick, TickSet},
+    spritesheet::{AnimationFinishedEvent, AnimationState, SpriteSheetAnimator},
+    state::{GameEvent, GameGlobals, GameState, NewLevelEvent},
+    ALL_ENEMIES_BONUS, CATCH_SCORE, ENEMY_SCORE, EXTRA_LIFE_REPEAT, EXTRA_LIFE_SCORE, FONT_SIZE,
+    MAX_PLAYERS,
+};
+use bevy::prelude::*;
+use std::num::NonZeroUsize;
+
+const POPUP_COLOR: Color = Color::rgb(0.0, 1.0, 1.0);
+
+pub struct ScorePlugin;
+
+impl Plugin for ScorePlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ScoreConfig {
+            all_enemies_bonus: Some(ALL_ENEMIES_BONUS),
+        })
+        .insert_resource(ExtraLifeConfig {
+            threshold: EXTRA_LIFE_SCORE,
+            repeat_every: EXTRA_LIFE_REPEAT,
+        })
+        .insert_resource(NextExtraLife([None; MAX_PLAYERS]))
+        .init_resource::<EnemyChain>()
+        .rollback_resource::<NextExtraLife>()
+        .rollback_resource::<EnemyChain>()
+        .add_systems(
+            Update,
+            (
+                reset_extra_lives.run_if(on_event::<GameEvent>()),
+                score_catches.run_if(on_event::<GameEvent>()),
+                reset_enemy_chain.run_if(on_event::<NewLevelEvent>()),
+                end_score_freeze.run_if(on_event::<AnimationFinishedEvent>()),
+            )
+                .chain(),
+        )
+        .add_systems(OnEnter(GameState::Ready), clear_score_popups)
+        .add_systems(OnEnter(GameState::Title), clear_score_popups)
+        .add_systems(
+            GameTick,
+            (
+                score_pickups.run_if(on_event::<PickupEvent>()),
+                score_killed_enemies.run_if(on_event::<EnemyKilledEvent>()),
+                award_extra_lives,
+            )
+                .chain()
+                .in_set(TickSet::Outcome)
+                .run_if(in_state(GameState::Playing)),
+        );
+    }
+}
+
+#[derive(Resource)]
+pub struct ScoreConfig {
+    /// Awarded once per level when every enemy out of its house is eaten on
+    /// every power pill
+    pub all_enemies_bonus: Option<usize>,
+}
+
+#[derive(Resource)]
+pub struct ExtraLifeConfig {
+    /// Score at which the first extra life is awarded
+    pub threshold: usize,
+    /// When set, another extra life is awarded every time the score grows
+    /// by this amount after the threshold
+    pub repeat_every: Option<NonZeroUsize>,
+}
+
+/// Score that awards the next extra life to each player, `None` once no more
+/// are left
+#[derive(Resource, Clone)]
+struct NextExtraLife([Option<usize>; MAX_PLAYERS]);
+
+/// Tracks the enemies eaten since the last power pill
+#[derive(Resource, Clone, Default)]
+struct EnemyChain {
+    eaten: usize,
+    /// Enemies out of their house when the last power pill was eaten, the
+    /// ones inside cannot be reached so they do not count for the bonus
+    edible: usize,
+    /// Progress of each player towards the bonus of the level they play
+    bonuses: [AllEnemiesBonus; MAX_PLAYERS],
+}
+
+#[derive(Clone, Copy, Default)]
+struct AllEnemiesBonus {
+    /// Power pills on which every enemy was eaten
+    perfect_pills: usize,
+    awarded: bool,
+}
+
+impl EnemyChain {
+    /// Each enemy eaten on the same power pill doubles the score:
+    /// 200, 400, 800 and then 1600 for every following one
+    const fn next_score(&self) -> usize {
+        let multiplier = if self.eaten < 3 { self.eaten } else { 3 };
+        ENEMY_SCORE << multiplier
+    }
+}
+
+/// The game time is paused while any of these is shown
+#[derive(Component)]
+struct ScorePopup;
+
+fn score_pickups(
+    mut pickup_reader: EventReader<PickupEvent>,
+    mut game_globals: ResMut<GameGlobals>,
+    mut enemy_chain: ResMut<EnemyChain>,
+    enemy_query: Query<(&Position, &EnemyMode), With<Enemy>>,
+    board: Res<Board>,
+) {
+    for pickup in pickup_reader.read() {
+        game_globals.get_progress_mut(pickup.player).score += pickup.value;
+        if pickup.kind == PickupKind::PowerPill {
+            enemy_chain.eaten = 0;
+            enemy_chain.edible = enemy_query
+                .iter()
+                .filter(|(position, mode)| {
+                    **mode != EnemyMode::Eaten && board.is_reachable(position)
+                })
+                .count();
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn score_killed_enemies(
+    mut commands: Commands,
+    mut event_reader: EventReader<EnemyKilledEvent>,
+    mut game_globals: ResMut<GameGlobals>,
+    mut enemy_chain: ResMut<EnemyChain>,
+    mut time: ResMut<Time<Virtual>>,
+    config: Res<ScoreConfig>,
+    board: Res<Board>,
+    font: Res<UiFont>,
+    animations: Res<GameAnimations>,
+    tick: Res<SimulationTick>,
+) {
+    for event in event_reader.read() {
+        let score = enemy_chain.next_score();
+        game_globals.get_progress_mut(event.player).score += score;
+        enemy_chain.eaten += 1;
+
+        let perfect = enemy_chain.eaten == enemy_chain.edible;
+        let progress = &mut enemy_chain.bonuses[game_globals.get_progress_index(event.player)];
+        if perfect {
+            progress.perfect_pills += 1;
+        }
+        if let Some(bonus) = config.all_enemies_bonus {
+            let power_pills = board.count_cells(CellType::PowerPill);
+            if !progress.awarded && progress.perfect_pills == power_pills {
+                progress.awarded = true;
+                game_globals.get_progress_mut(event.player).score += bonus;
+            }
+        }
+        if !tick.is_final() {
+            continue;
+        }
+
+        let mut transform = event.position.get_transform();
+        transform.translation.z = 10.;
+        commands.spawn((
+            Text2dBundle {
+                text: Text::from_section(
+                    score.to_string(),
+                    TextStyle {
+                        font: font.default.clone(),
+                        font_size: FONT_SIZE / 2.,
+                        color: POPUP_COLOR,
+                    },
+                ),
+                transform,
+                ..Default::default()
+            },
+            SpriteSheetAnimator::new(animations.effects.clone()),
+            AnimationState::Popup,
+            ScorePopup,
+            FloatingText,
+        ));
+
+        time.pause();
+    }
+}
+
+/// The game goes on once every popup was shown for the length of its clip
+fn end_score_freeze(
+    mut commands: Commands,
+    mut event_reader: EventReader<AnimationFinishedEvent>,
+    mut time: ResMut<Time<Virtual>>,
+    query: Query<Entity, With<ScorePopup>>,
+) {
+    let finished: Vec<_> = event_reader
+        .read()
+        .filter(|event| event.state == AnimationState::Popup)
+        .map(|event| event.entity)
+        .collect();
+    if finished.is_empty() {
+        return;
+    }
+    for entity in &finished {
+        commands.entity(*entity).despawn_recursive();
+    }
+    if query.iter().all(|entity| finished.contains(&entity)) {
+        time.unpause();
+    }
+}
+
+/// A level left from the pause menu during the freeze would otherwise start
+/// frozen, with the popup still shown
+fn clear_score_popups(
+    mut commands: Commands,
+    mut time: ResMut<Time<Virtual>>,
+    query: Query<Entity, With<ScorePopup>>,
+) {
+    for entity in &query {
+        commands.entity(entity).despawn_recursive();
+    }
+    time.unpause();
+}
+
+fn score_catches(mut event_reader: EventReader<GameEvent>, mut game_globals: ResMut<GameGlobals>) {
+    for event in event_reader.read() {
+        if let GameEvent::PlayerDies {
+            caught_by: Some(player),
+            ..
+        } = event
+        {
+            game_globals.players[*player].score += CATCH_SCORE;
+        }
+    }
+}
+
+fn reset_extra_lives(
+    config: Res<ExtraLifeConfig>,
+    mut next_extra_life: ResMut<NextExtraLife>,
+    mut event_reader: EventReader<GameEvent>,
+) {
+    if event_reader
+        .read()
+        .any(|event| matches!(event, GameEvent::NewGame { .. }))
+    {
+        next_extra_life.0 = [Some(config.threshold); MAX_PLAYERS];
+    }
+}
+
+/// Players resuming their turn keep the progress they made on their level,
+/// the others start over
+fn reset_enemy_chain(
+    mut enemy_chain: ResMut<EnemyChain>,
+    mut event_reader: EventReader<NewLevelEvent>,
+    game_globals: Res<GameGlobals>,
+) {
+    event_reader.clear();
+    enemy_chain.eaten = 0;
+    enemy_chain.edible = 0;
+    for (bonus, progress) in enemy_chain.bonuses.iter_mut().zip(&game_globals.players) {
+        if progress.pickups.is_none() {
+            *bonus = AllEnemiesBonus::default();
+        }
+    }
+}
+
+/// The life is given straight away, the event only lets the rest of the game
+/// know once the tick is final
+fn award_extra_lives(
+    mut game_globals: ResMut<GameGlobals>,
+    config: Res<ExtraLifeConfig>,
+    mut next_extra_life: ResMut<NextExtraLife>,
+    mut event_writer: EventWriter<GameEvent>,
+    tick: Res<SimulationTick>,
+) {
+    for (player, progress) in game_globals.players.iter_mut().enumerate() {
+        let next_extra_life = &mut next_extra_life.0[player];
+        while let Some(next_score) = *next_extra_life {
+            if progress.score < next_score {
+                break;
+            }
+            progress.lives += 1;
+            if tick.is_final() {
+                event_writer.send(GameEvent::ExtraLife { player });
+            }
+            *next_extra_life = config.repeat_every.map(|every| next_score + every.get());
+        }
+    }
+}
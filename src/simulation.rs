@@ -0,0 +1,245 @@
+use crate::{
+    input::InputDirectionEvent,
+    movement::Directions,
+    state::{GameEvent, GameState},
+    MAX_PLAYERS,
+};
+use bevy::{
+    ecs::schedule::{ExecutorKind, ScheduleLabel},
+    prelude::*,
+    utils::HashMap,
+};
+use rand::{rngs::StdRng, SeedableRng};
+
+/// Everything that changes the game while it is played runs here, one fixed
+/// step at a time. The same inputs always give the same result, which lets
+/// two instances play together and rewind when an input shows up late.
+pub struct SimulationPlugin;
+
+impl Plugin for SimulationPlugin {
+    fn build(&self, app: &mut App) {
+        // The single threaded executor always runs the systems in the same
+        // order, the multi threaded one does not
+        app.edit_schedule(GameTick, |schedule| {
+            schedule.set_executor_kind(ExecutorKind::SingleThreaded);
+        })
+        .configure_sets(
+            GameTick,
+            (
+                TickSet::Input,
+                TickSet::Movement,
+                TickSet::Collision,
+                TickSet::Outcome,
+            )
+                .chain(),
+        )
+        .init_resource::<TickDriver>()
+        .init_resource::<PendingInputs>()
+        .init_resource::<TickInputs>()
+        .init_resource::<SimulationTick>()
+        .insert_resource(SimRng(StdRng::from_entropy()))
+        .rollback_resource::<SimRng>()
+        .add_systems(First, resume_ticks)
+        .add_systems(
+            Update,
+            latch_inputs.run_if(on_event::<InputDirectionEvent>()),
+        )
+        .add_systems(
+            FixedUpdate,
+            run_game_tick.run_if(resource_equals(TickDriver::Local)),
+        );
+    }
+}
+
+/// Runs one step of the game
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GameTick;
+
+/// Copies the simulation state aside
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SaveSnapshot;
+
+/// Puts back the simulation state copied by [`SaveSnapshot`]
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LoadSnapshot;
+
+/// Order of the systems within a tick
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TickSet {
+    Input,
+    Movement,
+    Collision,
+    Outcome,
+}
+
+/// Who decides when the next tick runs
+#[derive(Resource, Default, PartialEq, Eq)]
+pub enum TickDriver {
+    /// Every fixed time step
+    #[default]
+    Local,
+    /// A network session, once the inputs of both players are around
+    Network,
+}
+
+/// Direction requested by each player for the tick being simulated
+#[derive(Resource, Default, Clone, Copy)]
+pub struct TickInputs {
+    pub directions: [Option<Directions>; MAX_PLAYERS],
+}
+
+/// Latest direction requested by each player since the last tick
+#[derive(Resource, Default)]
+pub struct PendingInputs(pub TickInputs);
+
+#[derive(Resource, Default)]
+pub struct SimulationTick {
+    /// Predicted ticks run before the inputs of every player are known and
+    /// will be simulated again, nothing outside of the simulation must
+    /// notice them
+    pub predicted: bool,
+    /// Set once a tick changed something outside of the simulation, no more
+    /// ticks run until the next frame has dealt with it
+    pub halted: bool,
+}
+
+impl SimulationTick {
+    pub const fn is_final(&self) -> bool {
+        !self.predicted
+    }
+}
+
+/// Events of a tick for the systems outside of the simulation. Predicted
+/// ticks are read too so their events do not pile up, but they come out
+/// empty as the tick will be simulated again.
+#[derive(bevy::ecs::system::SystemParam)]
+pub struct FinalTickEvents<'w, 's, E: Event> {
+    reader: EventReader<'w, 's, E>,
+    tick: Res<'w, SimulationTick>,
+}
+
+impl<E: Event> FinalTickEvents<'_, '_, E> {
+    pub fn read(&mut self) -> Vec<&E> {
+        let events: Vec<_> = self.reader.read().collect();
+        if self.tick.is_final() {
+            events
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+/// Random numbers used by the simulation, seeded the same way on every
+/// instance of a network game
+#[derive(Resource, Clone)]
+pub struct SimRng(pub StdRng);
+
+/// Marks the entities whose state belongs to the simulation
+#[derive(Component)]
+pub struct Rollback;
+
+/// Registers what the snapshots are made of
+pub trait RollbackApp {
+    /// Saves the component of every [`Rollback`] entity. Entities that did
+    /// not have it when saved lose it when loaded.
+    fn rollback_component<C: Component + Clone>(&mut self) -> &mut Self;
+    fn rollback_resource<R: Resource + Clone>(&mut self) -> &mut Self;
+}
+
+#[derive(Resource)]
+struct SavedComponents<C: Component>(HashMap<Entity, C>);
+
+#[derive(Resource)]
+struct SavedResource<R: Resource>(Option<R>);
+
+impl RollbackApp for App {
+    fn rollback_component<C: Component + Clone>(&mut self) -> &mut Self {
+        self.insert_resource(SavedComponents::<C>(HashMap::new()))
+            .add_systems(SaveSnapshot, save_components::<C>)
+            .add_systems(LoadSnapshot, load_components::<C>)
+    }
+
+    fn rollback_resource<R: Resource + Clone>(&mut self) -> &mut Self {
+        self.insert_resource(SavedResource::<R>(None))
+            .add_systems(SaveSnapshot, save_resource::<R>)
+            .add_systems(LoadSnapshot, load_resource::<R>)
+    }
+}
+
+fn save_components<C: Component + Clone>(
+    query: Query<(Entity, &C), With<Rollback>>,
+    mut saved: ResMut<SavedComponents<C>>,
+) {
+    saved.0.clear();
+    saved.0.extend(
+        query
+            .iter()
+            .map(|(entity, component)| (entity, component.clone())),
+    );
+}
+
+fn load_components<C: Component + Clone>(
+    mut commands: Commands,
+    mut query: Query<(Entity, Option<&mut C>), With<Rollback>>,
+    saved: Res<SavedComponents<C>>,
+) {
+    for (entity, component) in &mut query {
+        match (saved.0.get(&entity), component) {
+            (Some(value), Some(mut component)) => *component = value.clone(),
+            (Some(value), None) => {
+                commands.entity(entity).insert(value.clone());
+            }
+            (None, Some(_)) => {
+                commands.entity(entity).remove::<C>();
+            }
+            (None, None) => (),
+        }
+    }
+}
+
+fn save_resource<R: Resource + Clone>(resource: Res<R>, mut saved: ResMut<SavedResource<R>>) {
+    saved.0 = Some(resource.clone());
+}
+
+fn load_resource<R: Resource + Clone>(mut resource: ResMut<R>, saved: Res<SavedResource<R>>) {
+    if let Some(value) = &saved.0 {
+        *resource = value.clone();
+    }
+}
+
+/// Keeps the last direction of each player until the next tick takes it
+fn latch_inputs(
+    mut input_event_reader: EventReader<InputDirectionEvent>,
+    mut pending: ResMut<PendingInputs>,
+) {
+    for input_event in input_event_reader.read() {
+        if let Some(direction) = pending.0.directions.get_mut(input_event.player) {
+            *direction = Some(input_event.direction);
+        }
+    }
+}
+
+/// Runs one tick with the given inputs
+pub fn run_tick(world: &mut World, inputs: TickInputs, predicted: bool) {
+    let sent_events = world.resource::<Events<GameEvent>>().len();
+    *world.resource_mut::<TickInputs>() = inputs;
+    world.resource_mut::<SimulationTick>().predicted = predicted;
+    world.run_schedule(GameTick);
+
+    let halted = world.resource::<Events<GameEvent>>().len() != sent_events
+        || world.resource::<NextState<GameState>>().0.is_some()
+        || world.resource::<Time<Virtual>>().is_paused();
+    world.resource_mut::<SimulationTick>().halted |= halted;
+}
+
+fn run_game_tick(world: &mut World) {
+    if world.resource::<SimulationTick>().halted {
+        return;
+    }
+    let inputs = std::mem::take(&mut world.resource_mut::<PendingInputs>().0);
+    run_tick(world, inputs, false);
+}
+
+fn resume_ticks(mut tick: ResMut<SimulationTick>) {
+    tick.halted = false;
+}
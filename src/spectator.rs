@@ -0,0 +1,506 @@
+use crate::{
+    board::{Board, CellType},
+    enemies::{Enemy, EnemyMode},
+    movement::{Direction, Directions, Position},
+    pickup::{Dot, Pickup, PowerPill},
+    player::Player,
+    simulation::{GameTick, SimulationTick, TickSet},
+    state::GameGlobals,
+};
+use bevy::prelude::*;
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+};
+
+/// Publishes the state of the game after every tick to the tools connected
+/// to `--spectate PORT`, `--watch ADDRESS` runs the reference client.
+///
+/// Every frame is a little endian `u32` length followed by:
+/// * `u32` tick, counted since the game started publishing
+/// * `u16` fill, counted up every time the board is filled again. Pickups
+///   only ever disappear until it changes.
+/// * `u8` player count, then for each one `u32` score, `i8` lives and `u8`
+///   level
+/// * `u8` object count, then for each one `u8` kind (0 player, 1 enemy),
+///   `u8` index, `f32` x, `f32` y, `u8` direction (0 up, 1 down, 2 left,
+///   3 right) and `u8` mode (1 when frightened)
+/// * `u16` pickup count, then one bit for each dot and power pill of the
+///   board, row by row, set while it has not been eaten
+pub struct SpectatorPlugin;
+
+impl Plugin for SpectatorPlugin {
+    fn build(&self, app: &mut App) {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        let Some(port) = get_arg(&args, "--spectate") else {
+            return;
+        };
+        let Ok(port) = port.parse::<u16>() else {
+            error!("Invalid spectator port {port}");
+            return;
+        };
+        let address = SocketAddr::from(([127, 0, 0, 1], port));
+        let listener = match TcpListener::bind(address).and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        }) {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("Unable to publish the game on {address}: {err}");
+                return;
+            }
+        };
+        info!("Publishing the game on {address}");
+
+        app.insert_resource(Spectators {
+            listener,
+            streams: Vec::new(),
+            tick: 0,
+            fill: 0,
+        })
+        .add_systems(PreUpdate, accept_spectators)
+        .add_systems(
+            GameTick,
+            publish_state.after(TickSet::Outcome).run_if(has_spectators),
+        );
+    }
+}
+
+#[derive(Resource)]
+struct Spectators {
+    listener: TcpListener,
+    streams: Vec<TcpStream>,
+    tick: u32,
+    fill: u16,
+}
+
+/// Everything published about a single tick
+struct StateFrame {
+    tick: u32,
+    fill: u16,
+    players: Vec<PlayerFrame>,
+    objects: Vec<ObjectFrame>,
+    /// One entry for each cell of [`Board::get_pickup_cells`], set while the
+    /// pickup is still there
+    pickups: Vec<bool>,
+}
+
+struct PlayerFrame {
+    score: u32,
+    lives: i8,
+    level: u8,
+}
+
+struct ObjectFrame {
+    is_enemy: bool,
+    index: u8,
+    position: Position,
+    direction: Directions,
+    frightened: bool,
+}
+
+impl StateFrame {
+    fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(self.tick.to_le_bytes());
+        data.extend(self.fill.to_le_bytes());
+        data.push(self.players.len() as u8);
+        for player in &self.players {
+            data.extend(player.score.to_le_bytes());
+            data.extend(player.lives.to_le_bytes());
+            data.push(player.level);
+        }
+        data.push(self.objects.len() as u8);
+        for object in &self.objects {
+            data.push(u8::from(object.is_enemy));
+            data.push(object.index);
+            data.extend(object.position.x.to_le_bytes());
+            data.extend(object.position.y.to_le_bytes());
+            data.push(match object.direction {
+                Directions::Up => 0,
+                Directions::Down => 1,
+                Directions::Left => 2,
+                Directions::Right => 3,
+            });
+            data.push(u8::from(object.frightened));
+        }
+        data.extend((self.pickups.len() as u16).to_le_bytes());
+        for chunk in self.pickups.chunks(8) {
+            let byte = chunk
+                .iter()
+                .enumerate()
+                .fold(0u8, |byte, (bit, &set)| byte | (u8::from(set) << bit));
+            data.push(byte);
+        }
+
+        let mut frame = (data.len() as u32).to_le_bytes().to_vec();
+        frame.append(&mut data);
+        frame
+    }
+
+    /// Reads a frame without its length
+    fn decode(data: &[u8]) -> Option<Self> {
+        let mut reader = FrameReader(data);
+        let tick = reader.read_u32()?;
+        let fill = reader.read_u16()?;
+        let players = (0..reader.read_u8()?)
+            .map(|_| {
+                Some(PlayerFrame {
+                    score: reader.read_u32()?,
+                    lives: reader.read_u8()? as i8,
+                    level: reader.read_u8()?,
+                })
+            })
+            .collect::<Option<_>>()?;
+        let objects = (0..reader.read_u8()?)
+            .map(|_| {
+                Some(ObjectFrame {
+                    is_enemy: reader.read_u8()? == 1,
+                    index: reader.read_u8()?,
+                    position: Position::new(reader.read_f32()?, reader.read_f32()?),
+                    direction: match reader.read_u8()? {
+                        0 => Directions::Up,
+                        1 => Directions::Down,
+                        2 => Directions::Left,
+                        3 => Directions::Right,
+                        _ => return None,
+                    },
+                    frightened: reader.read_u8()? == 1,
+                })
+            })
+            .collect::<Option<_>>()?;
+        let pickup_count = usize::from(reader.read_u16()?);
+        let bytes = reader.read_bytes(pickup_count.div_ceil(8))?;
+        let pickups = (0..pickup_count)
+            .map(|index| bytes[index / 8] & (1 << (index % 8)) != 0)
+            .collect();
+        Some(Self {
+            tick,
+            fill,
+            players,
+            objects,
+            pickups,
+        })
+    }
+}
+
+struct FrameReader<'a>(&'a [u8]);
+
+impl FrameReader<'_> {
+    const fn read_bytes(&mut self, count: usize) -> Option<&[u8]> {
+        if self.0.len() < count {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(count);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn read_u8(&mut self) -> Option<u8> {
+        Some(self.read_bytes(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.read_bytes(2)?.try_into().ok()?))
+    }
+
+    fn read_u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.read_bytes(4)?.try_into().ok()?))
+    }
+
+    fn read_f32(&mut self) -> Option<f32> {
+        Some(f32::from_le_bytes(self.read_bytes(4)?.try_into().ok()?))
+    }
+}
+
+fn get_arg<'a>(args: &'a [String], flag: &str) -> Option<&'a String> {
+    let index = args.iter().position(|arg| arg == flag)?;
+    args.get(index + 1)
+}
+
+/// Address given to `--watch`, if any
+pub fn get_watch_address() -> Option<String> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    get_arg(&args, "--watch").cloned()
+}
+
+fn accept_spectators(mut spectators: ResMut<Spectators>) {
+    loop {
+        match spectators.listener.accept() {
+            Ok((stream, address)) => {
+                info!("Spectator {address} joined");
+                // A spectator too slow to keep up is dropped rather than
+                // holding the game back
+                if let Err(err) = stream.set_nonblocking(true) {
+                    error!("Unable to set up spectator {address}: {err}");
+                    continue;
+                }
+                spectators.streams.push(stream);
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+            Err(err) => {
+                error!("Unable to accept spectators: {err}");
+                break;
+            }
+        }
+    }
+}
+
+fn has_spectators(spectators: Option<Res<Spectators>>, tick: Res<SimulationTick>) -> bool {
+    tick.is_final() && spectators.is_some_and(|spectators| !spectators.streams.is_empty())
+}
+
+/// Rewinding only hides the pickups, new ones mean the board was filled
+/// again
+type NewPickup = Or<(Added<Dot>, Added<PowerPill>)>;
+
+fn publish_state(
+    mut spectators: ResMut<Spectators>,
+    players: Query<(&Player, &Position, &Direction)>,
+    enemies: Query<(&Enemy, &Position, &Direction, &EnemyMode)>,
+    pickups: Query<&Position, With<Pickup>>,
+    filled: Query<(), NewPickup>,
+    game_globals: Res<GameGlobals>,
+    board: Res<Board>,
+) {
+    if !filled.is_empty() {
+        spectators.fill = spectators.fill.wrapping_add(1);
+    }
+    let mut objects: Vec<_> = players
+        .iter()
+        .map(|(player, position, direction)| ObjectFrame {
+            is_enemy: false,
+            index: player.index as u8,
+            position: position.clone(),
+            direction: direction.current,
+            frightened: false,
+        })
+        .collect();
+    objects.extend(enemies.iter().map(|(enemy, position, direction, mode)| {
+        let index = board
+            .get_enemies()
+            .iter()
+            .position(|other| other.get_start_position() == enemy.get_start_position())
+            .unwrap_or_default();
+        ObjectFrame {
+            is_enemy: true,
+            index: index as u8,
+            position: position.clone(),
+            direction: direction.current,
+            frightened: *mode == EnemyMode::Frightened,
+        }
+    }));
+    objects.sort_by_key(|object| (object.is_enemy, object.index));
+
+    let remaining: Vec<_> = pickups.iter().collect();
+    let frame = StateFrame {
+        tick: spectators.tick,
+        fill: spectators.fill,
+        players: game_globals
+            .players
+            .iter()
+            .map(|progress| PlayerFrame {
+                score: progress.score as u32,
+                lives: progress.lives as i8,
+                level: progress.level as u8,
+            })
+            .collect(),
+        objects,
+        pickups: board
+            .get_pickup_cells()
+            .iter()
+            .map(|cell| remaining.contains(&cell))
+            .collect(),
+    };
+    spectators.tick += 1;
+
+    let data = frame.encode();
+    spectators
+        .streams
+        .retain_mut(|stream| match stream.write_all(&data) {
+            Ok(()) => true,
+            Err(err) => {
+                info!("Spectator left: {err}");
+                false
+            }
+        });
+}
+
+/// Reference client: replays the stream into a board of its own, checking
+/// that the frames make sense for it and printing a summary every second
+pub fn watch(address: &str) {
+    let mut stream = match TcpStream::connect(address) {
+        Ok(stream) => stream,
+        Err(err) => {
+            eprintln!("Unable to connect to {address}: {err}");
+            return;
+        }
+    };
+    let pickup_cells = Board::default().get_pickup_cells();
+    let mut board = Board::default();
+    let mut last_fill = None;
+    let mut last_tick = None;
+
+    loop {
+        let mut length = [0; 4];
+        if stream.read_exact(&mut length).is_err() {
+            println!("Stream closed");
+            return;
+        }
+        let mut data = vec![0; u32::from_le_bytes(length) as usize];
+        if let Err(err) = stream.read_exact(&mut data) {
+            eprintln!("Truncated frame: {err}");
+            return;
+        }
+        let Some(frame) = StateFrame::decode(&data) else {
+            eprintln!("Invalid frame");
+            return;
+        };
+
+        if frame.pickups.len() != pickup_cells.len() {
+            eprintln!(
+                "Tick {}: {} pickups sent, the board has {}",
+                frame.tick,
+                frame.pickups.len(),
+                pickup_cells.len()
+            );
+            return;
+        }
+        if last_tick.is_some_and(|last_tick| frame.tick != last_tick + 1) {
+            eprintln!("Tick {}: frames were skipped", frame.tick);
+        }
+        last_tick = Some(frame.tick);
+
+        // A board filled again starts from the pickups of the frame, which
+        // may be the ones left by the other player
+        if last_fill != Some(frame.fill) {
+            board = Board::default();
+            last_fill = Some(frame.fill);
+        } else if let Some(cell) = pickup_cells
+            .iter()
+            .zip(&frame.pickups)
+            .find(|(cell, &left)| left && !board.has_pickup(cell))
+            .map(|(cell, _)| cell)
+        {
+            eprintln!(
+                "Tick {}: the pickup at {}, {} came back",
+                frame.tick, cell.x, cell.y
+            );
+            return;
+        }
+        for (cell, _) in pickup_cells
+            .iter()
+            .zip(&frame.pickups)
+            .filter(|(_, &left)| !left)
+        {
+            board.clear_pickup(cell);
+        }
+
+        for object in &frame.objects {
+            let cell = Position::new(object.position.x.round(), object.position.y.round());
+            if matches!(board.get_cell(&cell), CellType::Wall(_)) {
+                eprintln!(
+                    "Tick {}: {} {} inside a wall at {}, {}",
+                    frame.tick,
+                    if object.is_enemy { "enemy" } else { "player" },
+                    object.index,
+                    object.position.x,
+                    object.position.y
+                );
+            }
+        }
+
+        if frame.tick.is_multiple_of(64) {
+            let left = frame.pickups.iter().filter(|&&left| left).count();
+            let scores: Vec<_> = frame
+                .players
+                .iter()
+                .map(|player| {
+                    format!(
+                        "{} (lives {}, level {})",
+                        player.score, player.lives, player.level
+                    )
+                })
+                .collect();
+            let frightened = frame.objects.iter().filter(|object| object.frightened);
+            println!(
+                "Tick {}: scores {}, {left} pickups left, {} enemies frightened",
+                frame.tick,
+                scores.join(", "),
+                frightened.count()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_round_trip() {
+        let frame = StateFrame {
+            tick: 1234,
+            fill: 3,
+            players: vec![
+                PlayerFrame {
+                    score: 12_340,
+                    lives: 2,
+                    level: 3,
+                },
+                PlayerFrame {
+                    score: 0,
+                    lives: -1,
+                    level: 1,
+                },
+            ],
+            objects: vec![
+                ObjectFrame {
+                    is_enemy: false,
+                    index: 0,
+                    position: Position::new(13.5, 23.),
+                    direction: Directions::Left,
+                    frightened: false,
+                },
+                ObjectFrame {
+                    is_enemy: true,
+                    index: 2,
+                    position: Position::new(-0.25, 14.),
+                    direction: Directions::Down,
+                    frightened: true,
+                },
+            ],
+            pickups: (0..11).map(|index| index % 3 != 0).collect(),
+        };
+
+        let data = frame.encode();
+        let (length, body) = data.split_at(4);
+        assert_eq!(
+            u32::from_le_bytes(length.try_into().unwrap()) as usize,
+            body.len()
+        );
+        let decoded = StateFrame::decode(body).unwrap();
+        assert_eq!(decoded.encode(), data);
+
+        assert_eq!(decoded.tick, frame.tick);
+        assert_eq!(decoded.fill, frame.fill);
+        assert_eq!(decoded.players[1].lives, -1);
+        assert!(decoded.objects[1].position == frame.objects[1].position);
+        assert!(decoded.objects[1].direction == Directions::Down);
+        assert!(decoded.objects[1].frightened);
+        assert_eq!(decoded.pickups, frame.pickups);
+    }
+
+    #[test]
+    fn truncated_frame_is_rejected() {
+        let frame = StateFrame {
+            tick: 1,
+            fill: 0,
+            players: vec![],
+            objects: vec![],
+            pickups: vec![true; 9],
+        };
+        let data = frame.encode();
+        assert!(StateFrame::decode(&data[4..data.len() - 1]).is_none());
+    }
+}
@@ -1,88 +1,390 @@
 use crate::{
-    movement::Direction,
-    player::{Player, PlayerState},
+    atlas::AtlasManifest,
+    game_assets::{GameAssets, GameAssetsLoader},
+    movement::Directions,
     state::GameState,
 };
-use bevy::prelude::*;
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    transform::TransformSystem,
+    utils::{BoxedFuture, HashMap},
+};
+use serde::Deserialize;
 
 pub struct AnimatedSpritePlugin;
 impl Plugin for AnimatedSpritePlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(
-            PostUpdate,
-            update_player
-                .run_if(in_state(PlayerState::Moving).and_then(in_state(GameState::Running))),
-        );
+        app.init_asset::<AnimationSet>()
+            .register_asset_loader(AnimationSetLoader)
+            .init_resource::<SetLayouts>()
+            .add_event::<AnimationFinishedEvent>()
+            .add_systems(
+                PostUpdate,
+                (build_layouts, animate_sprites)
+                    .chain()
+                    .before(TransformSystem::TransformPropagate),
+            );
     }
 }
 
 #[derive(Bundle)]
 pub struct AnimatedSpriteBundle {
     pub sprite_sheet_animator: SpriteSheetAnimator,
+    pub animation_state: AnimationState,
     pub spritesheet_bundle: SpriteSheetBundle,
 }
 
-#[derive(Component, Default)]
+/// Named clips of a sprite sheet, read from an `.anim.ron` file. Sets without
+/// a grid only keep time, their frame is read from the animator.
+#[derive(Asset, TypePath, Deserialize, Default)]
+pub struct AnimationSet {
+    /// Sprite of the atlas the grid is laid over
+    #[serde(default)]
+    sprite: Option<GameAssets>,
+    #[serde(default)]
+    frame_size: Vec2,
+    #[serde(default)]
+    columns: usize,
+    #[serde(default)]
+    rows: usize,
+    /// A clip named after a state and a direction, like `walk_up`, is used
+    /// as it is. Otherwise the clip named after the state is turned to face
+    /// the direction.
+    clips: HashMap<String, AnimationClip>,
+    #[serde(default)]
+    facing: HashMap<Directions, Facing>,
+}
+
+/// Layout of each animation set over its sprite in the current atlas
+#[derive(Resource, Default)]
+struct SetLayouts(HashMap<AssetId<AnimationSet>, Handle<TextureAtlasLayout>>);
+
+#[derive(Deserialize)]
+pub struct AnimationClip {
+    first: usize,
+    last: usize,
+    frame_rate: f32,
+    #[serde(default)]
+    strategy: AnimationStrategy,
+    /// Keeps playing while the game time is frozen
+    #[serde(default)]
+    real_time: bool,
+}
+
+/// How the frames of a clip drawn facing left are turned to face another
+/// direction
+#[derive(Deserialize, Default, Clone, Copy)]
+struct Facing {
+    /// Degrees, counterclockwise
+    #[serde(default)]
+    rotation: f32,
+    #[serde(default)]
+    flip_x: bool,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+pub enum AnimationStrategy {
+    #[default]
+    Loop,
+    PingPong,
+    /// Stops on the last frame and sends an [`AnimationFinishedEvent`]
+    Once,
+}
+
+/// Sent once an entity played the whole clip of its state, for clips
+/// played [`AnimationStrategy::Once`] and states without a clip
+#[derive(Event)]
+pub struct AnimationFinishedEvent {
+    pub entity: Entity,
+    pub state: AnimationState,
+}
+
+/// What the entity is doing, each state plays its own clip
+#[derive(Component, Clone, Copy, PartialEq, Eq)]
+pub enum AnimationState {
+    Walk(Directions),
+    Idle(Directions),
+    Frightened,
+    /// Frightened, about to calm down
+    Flashing,
+    Death,
+    /// Only the eyes of an enemy, looking that way
+    Eyes(Directions),
+    /// The walls flashing once the board is cleared
+    MazeFlash,
+    /// The points scored for an enemy, shown while the game is frozen
+    Popup,
+}
+
+impl AnimationState {
+    const fn get_name(self) -> &'static str {
+        match self {
+            Self::Walk(_) => "walk",
+            Self::Idle(_) => "idle",
+            Self::Frightened => "frightened",
+            Self::Flashing => "flashing",
+            Self::Death => "death",
+            Self::Eyes(_) => "eyes",
+            Self::MazeFlash => "maze_flash",
+            Self::Popup => "popup",
+        }
+    }
+
+    const fn get_direction(self) -> Option<Directions> {
+        match self {
+            Self::Walk(direction) | Self::Idle(direction) | Self::Eyes(direction) => {
+                Some(direction)
+            }
+            Self::Frightened | Self::Flashing | Self::Death | Self::MazeFlash | Self::Popup => None,
+        }
+    }
+
+    /// State played instead when the set has no clip for this one
+    const fn get_fallback(self) -> Option<Self> {
+        match self {
+            Self::Flashing => Some(Self::Frightened),
+            Self::Idle(direction) => Some(Self::Walk(direction)),
+            Self::Walk(_)
+            | Self::Frightened
+            | Self::Death
+            | Self::Eyes(_)
+            | Self::MazeFlash
+            | Self::Popup => None,
+        }
+    }
+}
+
+impl AnimationSet {
+    /// Lays the grid over the region of the sprite in the atlas
+    fn build_layout(&self, manifest: &AtlasManifest) -> Option<TextureAtlasLayout> {
+        if self.columns * self.rows == 0 {
+            return None;
+        }
+        let offset = match self.sprite {
+            Some(sprite) => {
+                let Some(region) = manifest.get_region(sprite) else {
+                    error!("{sprite:?} is missing from the atlas manifest");
+                    return None;
+                };
+                Some(region.min)
+            }
+            None => None,
+        };
+        Some(TextureAtlasLayout::from_grid(
+            self.frame_size,
+            self.columns,
+            self.rows,
+            None,
+            offset,
+        ))
+    }
+
+    fn get_clip(&self, state: AnimationState) -> Option<(&AnimationClip, Facing)> {
+        let name = state.get_name();
+        let direction = state.get_direction();
+        let directional = direction.and_then(|direction| {
+            let suffix = match direction {
+                Directions::Up => "up",
+                Directions::Down => "down",
+                Directions::Left => "left",
+                Directions::Right => "right",
+            };
+            self.clips.get(&format!("{name}_{suffix}"))
+        });
+        let found = directional
+            .map(|clip| (clip, Facing::default()))
+            .or_else(|| {
+                let facing = direction.and_then(|direction| self.facing.get(&direction));
+                let facing = facing.copied().unwrap_or_default();
+                self.clips.get(name).map(|clip| (clip, facing))
+            });
+        found.or_else(|| self.get_clip(state.get_fallback()?))
+    }
+}
+
+#[derive(Component)]
 pub struct SpriteSheetAnimator {
-    pub start: usize,
-    pub end: usize,
-    pub frame_rate: f32,
-    pub strategy: AnimationStrategy,
-    pub texture_atlas_layout: Handle<TextureAtlasLayout>,
+    pub animations: Handle<AnimationSet>,
+    /// Seconds since the current clip started
     pub time: f32,
+    /// Index of the frame being shown
+    pub frame: usize,
+    /// Whether the [`AnimationFinishedEvent`] of the current state was sent
+    finished: bool,
 }
 
 impl SpriteSheetAnimator {
-    pub fn update_index(&mut self, texture_atlas: &mut TextureAtlas, delta: f32) {
-        self.time += delta;
-        let time = (self.time * self.frame_rate) as usize;
-        let animation_length = 1 + self.end - self.start;
+    pub const fn new(animations: Handle<AnimationSet>) -> Self {
+        Self {
+            animations,
+            time: 0.,
+            frame: 0,
+            finished: false,
+        }
+    }
+}
+
+impl AnimationClip {
+    fn get_index(&self, time: f32) -> usize {
+        let time = (time * self.frame_rate) as usize;
+        let animation_length = 1 + self.last - self.first;
 
-        let index = match self.strategy {
+        let offset = match self.strategy {
             AnimationStrategy::PingPong => {
-                if (time / animation_length % 2) == 0 {
+                if (time / animation_length).is_multiple_of(2) {
                     time % animation_length
                 } else {
                     animation_length - 1 - (time % animation_length)
                 }
             }
-            AnimationStrategy::Loop => {
-                (self.time * self.frame_rate) as usize % (1 + self.end - self.start)
-            }
+            AnimationStrategy::Loop => time % animation_length,
+            AnimationStrategy::Once => time.min(animation_length - 1),
         };
-        texture_atlas.index = index;
+        self.first + offset
+    }
+
+    fn is_finished(&self, time: f32) -> bool {
+        let time = (time * self.frame_rate) as usize;
+        matches!(self.strategy, AnimationStrategy::Once) && time > self.last - self.first
     }
 }
 
-#[derive(Default)]
-pub enum AnimationStrategy {
-    #[default]
-    Loop,
-    PingPong,
+struct AnimationSetLoader;
+
+#[derive(Debug)]
+pub enum AnimationSetLoaderError {
+    Io(std::io::Error),
+    Ron(ron::error::SpannedError),
+}
+
+impl std::fmt::Display for AnimationSetLoaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "Unable to read the animation set: {err}"),
+            Self::Ron(err) => write!(f, "Invalid animation set: {err}"),
+        }
+    }
 }
 
-fn update_player(
-    mut query: Query<
-        (
-            &Direction,
-            &mut Transform,
-            &mut Sprite,
-            &mut TextureAtlas,
-            &mut SpriteSheetAnimator,
-        ),
-        With<Player>,
-    >,
+impl std::error::Error for AnimationSetLoaderError {}
+
+impl AssetLoader for AnimationSetLoader {
+    type Asset = AnimationSet;
+    type Settings = ();
+    type Error = AnimationSetLoaderError;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<AnimationSet, AnimationSetLoaderError>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader
+                .read_to_end(&mut bytes)
+                .await
+                .map_err(AnimationSetLoaderError::Io)?;
+            ron::de::from_bytes(&bytes).map_err(AnimationSetLoaderError::Ron)
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["anim.ron"]
+    }
+}
+
+/// Lays the animation sets over the atlas again whenever one of them, the
+/// manifest or the atlas itself changes
+fn build_layouts(
+    mut set_events: EventReader<AssetEvent<AnimationSet>>,
+    mut manifest_events: EventReader<AssetEvent<AtlasManifest>>,
+    mut set_layouts: ResMut<SetLayouts>,
+    mut layouts: ResMut<Assets<TextureAtlasLayout>>,
+    animation_sets: Res<Assets<AnimationSet>>,
+    manifests: Res<Assets<AtlasManifest>>,
+    game_assets: Res<GameAssetsLoader>,
+) {
+    let events = set_events.read().count() + manifest_events.read().count();
+    if events == 0 && !game_assets.is_changed() {
+        return;
+    }
+    let Some(manifest) = game_assets.get_manifest(&manifests) else {
+        return;
+    };
+    set_layouts.0.clear();
+    for (id, set) in animation_sets.iter() {
+        if let Some(layout) = set.build_layout(manifest) {
+            set_layouts.0.insert(id, layouts.add(layout));
+        }
+    }
+}
+
+/// Plays the clip of the current state of every animated entity, starting
+/// it over when the state changes. Sprites only move while the round is
+/// being played or coming to an end.
+#[allow(clippy::type_complexity)]
+fn animate_sprites(
+    mut query: Query<(
+        Entity,
+        &mut SpriteSheetAnimator,
+        Ref<AnimationState>,
+        Option<&mut TextureAtlas>,
+        Option<&mut Transform>,
+        Option<&mut Sprite>,
+    )>,
+    mut finished_writer: EventWriter<AnimationFinishedEvent>,
+    animation_sets: Res<Assets<AnimationSet>>,
+    set_layouts: Res<SetLayouts>,
+    state: Res<State<GameState>>,
     time: Res<Time>,
+    real_time: Res<Time<Real>>,
 ) {
-    let (dir, mut transform, mut sprite, mut atlas, mut animator) = query.single_mut();
-    let (rot, flip) = match dir.current {
-        crate::movement::Directions::Up => (270f32.to_radians(), false),
-        crate::movement::Directions::Down => (90f32.to_radians(), false),
-        crate::movement::Directions::Left => (0f32.to_radians(), false),
-        crate::movement::Directions::Right => (0f32.to_radians(), true),
+    let (delta, real_delta) = match state.get() {
+        GameState::Playing | GameState::Dying | GameState::LevelComplete => {
+            (time.delta_seconds(), real_time.delta_seconds())
+        }
+        _ => (0., 0.),
     };
-    transform.rotation = Quat::from_rotation_z(rot);
-    sprite.flip_x = flip;
+    for (entity, mut animator, animation_state, atlas, transform, sprite) in &mut query {
+        let Some(set) = animation_sets.get(&animator.animations) else {
+            continue;
+        };
+        let clip = set.get_clip(*animation_state);
+        if animation_state.is_changed() {
+            animator.time = 0.;
+            animator.finished = false;
+        } else if let Some((clip, _)) = clip {
+            animator.time += if clip.real_time { real_delta } else { delta };
+        }
+
+        let finished = clip.is_none_or(|(clip, _)| clip.is_finished(animator.time));
+        if finished && !animator.finished {
+            animator.finished = true;
+            finished_writer.send(AnimationFinishedEvent {
+                entity,
+                state: *animation_state,
+            });
+        }
+        let Some((clip, facing)) = clip else {
+            continue;
+        };
 
-    animator.update_index(&mut atlas, time.delta_seconds());
+        animator.frame = clip.get_index(animator.time);
+        if let Some(mut atlas) = atlas {
+            if let Some(layout) = set_layouts.0.get(&animator.animations.id()) {
+                if atlas.layout != *layout {
+                    atlas.layout = layout.clone();
+                }
+            }
+            atlas.index = animator.frame;
+        }
+        if let Some(mut transform) = transform {
+            transform.rotation = Quat::from_rotation_z(facing.rotation.to_radians());
+        }
+        if let Some(mut sprite) = sprite {
+            sprite.flip_x = facing.flip_x;
+        }
+    }
 }
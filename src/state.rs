@@ -1,11 +1,57 @@
-use crate::INTIAL_LIVES;
+use crate::{
+    input::{InputDirectionEvent, Inputs},
+    movement::Position,
+    simulation::{LoadSnapshot, SaveSnapshot, TickDriver},
+    spritesheet::{AnimationFinishedEvent, AnimationState},
+    ATTRACT_DELAY, GAME_OVER_TIME, INTIAL_LIVES, READY_TIME,
+};
 use bevy::prelude::*;
 
 #[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, States)]
 pub enum GameState {
+    /// Waits for the assets before showing the title screen
     #[default]
-    Running,
+    Loading,
+    Title,
+    Menu,
+    Ready,
+    Playing,
     Paused,
+    Dying,
+    LevelComplete,
+    GameOver,
+}
+
+impl GameState {
+    /// Time spent in the state before moving on by itself
+    const fn get_duration(self) -> Option<f32> {
+        match self {
+            Self::Title => Some(ATTRACT_DELAY),
+            Self::Ready => Some(READY_TIME),
+            Self::GameOver => Some(GAME_OVER_TIME),
+            Self::Loading
+            | Self::Menu
+            | Self::Playing
+            | Self::Paused
+            | Self::Dying
+            | Self::LevelComplete => None,
+        }
+    }
+
+    /// Animation that moves on from the state once it finished playing
+    const fn get_animation(self) -> Option<AnimationState> {
+        match self {
+            Self::Dying => Some(AnimationState::Death),
+            Self::LevelComplete => Some(AnimationState::MazeFlash),
+            Self::Loading
+            | Self::Title
+            | Self::Menu
+            | Self::Ready
+            | Self::Playing
+            | Self::Paused
+            | Self::GameOver => None,
+        }
+    }
 }
 
 pub struct StatePlugin;
@@ -13,26 +59,188 @@ pub struct StatePlugin;
 impl Plugin for StatePlugin {
     fn build(&self, app: &mut App) {
         app.init_state::<GameState>()
-            .insert_resource(GameGlobals {
-                lives: INTIAL_LIVES,
-                ..default()
-            })
-            .add_systems(Update, process_game_events.run_if(on_event::<GameEvent>()));
+            .init_resource::<GameGlobals>()
+            .init_resource::<StateTimer>()
+            .init_resource::<SavedProgress>()
+            .add_event::<NewLevelEvent>()
+            .add_systems(SaveSnapshot, save_progress)
+            .add_systems(LoadSnapshot, load_progress)
+            .add_systems(
+                Update,
+                (
+                    process_game_events.run_if(on_event::<GameEvent>()),
+                    reset_state_timer.run_if(state_changed::<GameState>),
+                    update_state_timer,
+                )
+                    .chain(),
+            )
+            .add_systems(OnEnter(GameState::Title), stop_attract_mode);
     }
 }
 
 #[derive(Event)]
 pub enum GameEvent {
     TogglePause,
-    PlayerDies,
+    /// The given player entity touched a chasing enemy, driven by another
+    /// player in versus games
+    PlayerDies {
+        player: usize,
+        caught_by: Option<usize>,
+        /// Where the player was caught
+        position: Position,
+    },
+    /// Awarded to the given player progress, the life has already been
+    /// added
+    ExtraLife {
+        player: usize,
+    },
+    /// Starts a new game for the given number of players, as a demo when
+    /// `attract_mode` is set
+    NewGame {
+        attract_mode: bool,
+        players: usize,
+        mode: GameMode,
+    },
+    /// Fills the board again and restarts the current level, keeping the
+    /// score and the lives
+    RestartLevel,
+    /// Leaves the game and goes back to the title screen
+    ExitToTitle,
 }
 
-#[derive(Default, Resource)]
+/// Sent every time the board has to be filled again, either because a new
+/// game started, the previous level was cleared or the players took turns.
+/// The pickups left by the current player are restored, if any.
+#[derive(Event)]
+pub struct NewLevelEvent;
+
+/// How the players share the board
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum GameMode {
+    /// One player at a time, taking turns every time a life is lost
+    #[default]
+    Alternating,
+    /// Every player is on the board at the same time
+    Coop,
+    /// The second player drives one of the enemies
+    Versus,
+}
+
+#[derive(Resource)]
 pub struct GameGlobals {
-    pub score: usize,
+    /// One entry for each player in the game
+    pub players: Vec<PlayerProgress>,
+    /// Index of the player whose turn it is
+    pub current_player: usize,
+    pub mode: GameMode,
     pub show_grid: bool,
     pub is_debug: bool,
+    /// The player is driven by the AI while the title screen is idle
+    pub attract_mode: bool,
+}
+
+impl Default for GameGlobals {
+    fn default() -> Self {
+        Self {
+            players: vec![PlayerProgress::default()],
+            current_player: 0,
+            mode: GameMode::default(),
+            show_grid: false,
+            is_debug: false,
+            attract_mode: false,
+        }
+    }
+}
+
+impl GameGlobals {
+    pub fn get_player(&self) -> &PlayerProgress {
+        &self.players[self.current_player]
+    }
+
+    pub fn get_player_mut(&mut self) -> &mut PlayerProgress {
+        &mut self.players[self.current_player]
+    }
+
+    /// Progress fed by the given player entity. Players taking turns share a
+    /// single entity, which always plays for the current player.
+    pub const fn get_progress_index(&self, player: usize) -> usize {
+        match self.mode {
+            GameMode::Alternating => self.current_player,
+            GameMode::Coop | GameMode::Versus => player,
+        }
+    }
+
+    /// Number of player entities on the board at the same time
+    pub const fn get_players_on_board(&self) -> usize {
+        match self.mode {
+            GameMode::Alternating => 1,
+            GameMode::Coop | GameMode::Versus => self.players.len(),
+        }
+    }
+
+    pub fn get_progress(&self, player: usize) -> &PlayerProgress {
+        &self.players[self.get_progress_index(player)]
+    }
+
+    pub fn get_progress_mut(&mut self, player: usize) -> &mut PlayerProgress {
+        let index = self.get_progress_index(player);
+        &mut self.players[index]
+    }
+
+    /// Player who plays after the current one, the current one again when
+    /// everybody else is out of lives and `None` once the game is over
+    fn get_next_player(&self) -> Option<usize> {
+        let count = self.players.len();
+        match self.mode {
+            GameMode::Alternating => (1..=count)
+                .map(|offset| (self.current_player + offset) % count)
+                .find(|&index| self.players[index].lives >= 0),
+            // Everybody plays at once, the game goes on while anybody has
+            // lives left
+            GameMode::Coop => self
+                .players
+                .iter()
+                .any(|progress| progress.lives >= 0)
+                .then_some(self.current_player),
+            // Only the first player can lose lives, the other one is an enemy
+            GameMode::Versus => (self.players[0].lives >= 0).then_some(self.current_player),
+        }
+    }
+}
+
+/// Everything that belongs to a single player. When taking turns it is stored
+/// away while the other player plays.
+#[derive(Clone)]
+pub struct PlayerProgress {
+    pub score: usize,
     pub lives: isize,
+    pub level: usize,
+    /// Pickups that were still on the board when the turn ended, `None`
+    /// means the level has not been touched yet
+    pub pickups: Option<Vec<Position>>,
+}
+
+impl Default for PlayerProgress {
+    fn default() -> Self {
+        Self {
+            score: 0,
+            lives: INTIAL_LIVES,
+            level: 1,
+            pickups: None,
+        }
+    }
+}
+
+/// Drives the states that move on by themselves after a while, the others
+/// wait for their animation
+#[derive(Resource, Default)]
+struct StateTimer(Option<Timer>);
+
+/// The part of [`GameGlobals`] changed by the simulation
+#[derive(Resource, Default)]
+struct SavedProgress {
+    players: Vec<PlayerProgress>,
+    current_player: usize,
 }
 
 fn process_game_events(
@@ -40,19 +248,148 @@ fn process_game_events(
     state: Res<State<GameState>>,
     mut event_reader: EventReader<GameEvent>,
     mut globals: ResMut<GameGlobals>,
+    mut level_writer: EventWriter<NewLevelEvent>,
+    driver: Res<TickDriver>,
 ) {
+    // The other side of a network game would keep playing the level, so it
+    // can neither be paused nor restarted
+    let online = *driver == TickDriver::Network;
+    let mut dead_players = Vec::new();
     for event in event_reader.read() {
         match event {
-            GameEvent::TogglePause => {
-                if *state.get() == GameState::Running {
+            GameEvent::TogglePause => match state.get() {
+                GameState::Playing if !globals.attract_mode && !online => {
                     next_state.set(GameState::Paused);
-                } else {
-                    next_state.set(GameState::Running);
                 }
+                GameState::Paused => next_state.set(GameState::Playing),
+                _ => (),
+            },
+            GameEvent::PlayerDies { player, .. } => {
+                // Touching more than one enemy at once only costs one life
+                if *state.get() == GameState::Playing && !dead_players.contains(player) {
+                    dead_players.push(*player);
+                    globals.get_progress_mut(*player).lives -= 1;
+                    next_state.set(GameState::Dying);
+                }
+            }
+            GameEvent::ExtraLife { player } => {
+                info!("Extra life for player {}", player + 1);
+            }
+            GameEvent::NewGame {
+                attract_mode,
+                players,
+                mode,
+            } => {
+                *globals = GameGlobals {
+                    players: (0..*players).map(|_| PlayerProgress::default()).collect(),
+                    mode: *mode,
+                    attract_mode: *attract_mode,
+                    show_grid: globals.show_grid,
+                    is_debug: globals.is_debug,
+                    ..default()
+                };
+                level_writer.send(NewLevelEvent);
+                next_state.set(GameState::Ready);
+            }
+            GameEvent::RestartLevel if !online => {
+                globals.get_player_mut().pickups = None;
+                level_writer.send(NewLevelEvent);
+                next_state.set(GameState::Ready);
+            }
+            GameEvent::RestartLevel => (),
+            GameEvent::ExitToTitle => {
+                next_state.set(GameState::Title);
+            }
+        }
+    }
+}
+
+fn reset_state_timer(state: Res<State<GameState>>, mut state_timer: ResMut<StateTimer>) {
+    state_timer.0 = state
+        .get()
+        .get_duration()
+        .map(|duration| Timer::from_seconds(duration, TimerMode::Once));
+}
+
+#[allow(clippy::too_many_arguments)]
+fn update_state_timer(
+    state: Res<State<GameState>>,
+    mut next_state: ResMut<NextState<GameState>>,
+    mut state_timer: ResMut<StateTimer>,
+    mut globals: ResMut<GameGlobals>,
+    mut level_writer: EventWriter<NewLevelEvent>,
+    mut game_event_writer: EventWriter<GameEvent>,
+    mut animation_reader: EventReader<AnimationFinishedEvent>,
+    mut direction_reader: EventReader<InputDirectionEvent>,
+    inputs: Inputs,
+    touches: Res<Touches>,
+    time: Res<Time<Real>>,
+) {
+    // Sticks only show up as directions
+    let active = direction_reader.read().count() > 0
+        || inputs.get_any_just_pressed().is_some()
+        || touches.any_just_pressed();
+    let animation = state.get().get_animation();
+    let animation_finished = animation_reader
+        .read()
+        .any(|event| Some(event.state) == animation);
+    let timer_finished = state_timer.0.as_mut().is_some_and(|timer| {
+        // Any activity on the title screen delays the attract mode
+        if *state.get() == GameState::Title && active {
+            timer.reset();
+        }
+        timer.tick(time.delta()).just_finished()
+    });
+    if !timer_finished && !animation_finished {
+        return;
+    }
+
+    match state.get() {
+        GameState::Title => {
+            game_event_writer.send(GameEvent::NewGame {
+                attract_mode: true,
+                players: 1,
+                mode: GameMode::Alternating,
+            });
+        }
+        GameState::Ready => next_state.set(GameState::Playing),
+        // Players take turns every time one of them loses a life
+        GameState::Dying => match globals.get_next_player() {
+            Some(next_player) => {
+                if next_player != globals.current_player {
+                    globals.current_player = next_player;
+                    level_writer.send(NewLevelEvent);
+                }
+                next_state.set(GameState::Ready);
             }
-            GameEvent::PlayerDies => {
-                globals.lives -= 1;
+            None => next_state.set(GameState::GameOver),
+        },
+        GameState::LevelComplete => {
+            let (mode, current_player) = (globals.mode, globals.current_player);
+            for (index, player) in globals.players.iter_mut().enumerate() {
+                if mode == GameMode::Coop || index == current_player {
+                    player.level += 1;
+                    player.pickups = None;
+                }
             }
+            level_writer.send(NewLevelEvent);
+            next_state.set(GameState::Ready);
         }
+        GameState::GameOver => next_state.set(GameState::Title),
+        GameState::Loading | GameState::Menu | GameState::Playing | GameState::Paused => (),
     }
 }
+
+fn stop_attract_mode(mut globals: ResMut<GameGlobals>) {
+    globals.attract_mode = false;
+}
+
+fn save_progress(globals: Res<GameGlobals>, mut saved: ResMut<SavedProgress>) {
+    saved.players.clone_from(&globals.players);
+    saved.current_player = globals.current_player;
+}
+
+fn load_progress(mut globals: ResMut<GameGlobals>, saved: Res<SavedProgress>) {
+    globals.players.clone_from(&saved.players);
+    globals.current_player = saved.current_player;
+}
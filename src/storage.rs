@@ -0,0 +1,71 @@
+use bevy::log::error;
+use serde::{de::DeserializeOwned, Serialize};
+
+// Native builds keep one RON file per setting group in the config folder,
+// the web build uses the browser localStorage instead
+#[cfg(not(target_family = "wasm"))]
+const CONFIG_FOLDER: &str = "config";
+#[cfg(target_family = "wasm")]
+const STORAGE_PREFIX: &str = "capman-rs.";
+
+/// Reads a setting group, returns `None` when it was never saved or it cannot
+/// be parsed anymore
+pub fn load<T: DeserializeOwned>(name: &str) -> Option<T> {
+    let data = read(name)?;
+    match ron::from_str(&data) {
+        Ok(value) => Some(value),
+        Err(err) => {
+            error!("Invalid settings file {name}: {err}");
+            None
+        }
+    }
+}
+
+pub fn save<T: Serialize>(name: &str, value: &T) {
+    let pretty = ron::ser::PrettyConfig::default();
+    match ron::ser::to_string_pretty(value, pretty) {
+        Ok(data) => write(name, &data),
+        Err(err) => error!("Unable to serialize settings {name}: {err}"),
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+fn read(name: &str) -> Option<String> {
+    let path = std::path::Path::new(CONFIG_FOLDER).join(format!("{name}.ron"));
+    std::fs::read_to_string(path).ok()
+}
+
+#[cfg(not(target_family = "wasm"))]
+fn write(name: &str, data: &str) {
+    let path = std::path::Path::new(CONFIG_FOLDER).join(format!("{name}.ron"));
+    let result = std::fs::create_dir_all(CONFIG_FOLDER).and_then(|()| std::fs::write(&path, data));
+    if let Err(err) = result {
+        error!("Unable to save settings to {}: {err}", path.display());
+    }
+}
+
+#[cfg(target_family = "wasm")]
+fn local_storage() -> Option<web_sys::Storage> {
+    web_sys::window()?.local_storage().ok()?
+}
+
+#[cfg(target_family = "wasm")]
+fn read(name: &str) -> Option<String> {
+    local_storage()?
+        .get_item(&format!("{STORAGE_PREFIX}{name}"))
+        .ok()?
+}
+
+#[cfg(target_family = "wasm")]
+fn write(name: &str, data: &str) {
+    let Some(storage) = local_storage() else {
+        error!("localStorage is not available, settings {name} not saved");
+        return;
+    };
+    if storage
+        .set_item(&format!("{STORAGE_PREFIX}{name}"), data)
+        .is_err()
+    {
+        error!("Unable to save settings {name} to localStorage");
+    }
+}
@@ -0,0 +1,372 @@
+use crate::{
+    atlas::{ATLAS, ATLAS_MANIFEST},
+    game_assets::{GameAssetsLoader, LoadingQueue, UiFont, UI_FONT},
+    storage,
+};
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext, ReadAssetBytesError},
+    ecs::system::SystemParam,
+    prelude::*,
+    utils::BoxedFuture,
+};
+use serde::{Deserialize, Serialize};
+
+const THEME_STORAGE: &str = "theme";
+/// Folders of the theme packs, each one holding a `theme.ron` manifest
+const THEME_INDEX: &str = "themes/index.themes.ron";
+/// Shown in the settings when no pack is selected
+const DEFAULT_THEME_NAME: &str = "CLASSIC";
+const FRIGHTENED_COLOR: Color = Color::rgb(0.3, 0.3, 1.0);
+const FRIGHTENED_FLASH_COLOR: Color = Color::WHITE;
+/// Body colour of each enemy, in the order they appear on the board
+const ENEMY_COLORS: [Color; 4] = [
+    Color::rgb(1.0, 0.2, 0.2),
+    Color::rgb(1.0, 0.6, 0.85),
+    Color::rgb(0.3, 0.9, 1.0),
+    Color::rgb(1.0, 0.65, 0.3),
+];
+const CLASSIC_DOORS: Color = Color::rgb(1.0, 0.72, 0.85);
+const CLASSIC_DOTS: Color = Color::rgb(1.0, 0.72, 0.6);
+/// Wall colour of each level, starting over after the last one
+const CLASSIC_WALLS: [Color; 4] = [
+    Color::rgb(0.13, 0.13, 1.0),
+    Color::rgb(1.0, 0.45, 0.2),
+    Color::rgb(0.2, 0.8, 0.35),
+    Color::rgb(0.75, 0.3, 1.0),
+];
+/// The walls turn white while the cleared board flashes
+const FLASH_COLORS: MazeColors = MazeColors {
+    walls: Color::WHITE,
+    doors: Color::WHITE,
+    dots: Color::WHITE,
+};
+
+/// Swaps the sprites, the font and the colours of the game for the ones of a
+/// theme pack, while the game runs
+pub struct ThemePlugin;
+
+impl Plugin for ThemePlugin {
+    fn build(&self, app: &mut App) {
+        let settings = storage::load(THEME_STORAGE).unwrap_or_default();
+        app.insert_resource::<ThemeSettings>(settings)
+            .init_resource::<Palette>()
+            .init_resource::<ThemeLoader>()
+            .init_asset::<ThemePacks>()
+            .register_asset_loader(ThemePacksLoader)
+            .add_systems(Startup, load_theme_packs)
+            .add_systems(
+                Update,
+                (
+                    apply_theme.run_if(
+                        resource_changed::<ThemeSettings>
+                            .or_else(on_event::<AssetEvent<ThemePacks>>()),
+                    ),
+                    save_theme_settings.run_if(
+                        resource_changed::<ThemeSettings>
+                            .and_then(not(resource_added::<ThemeSettings>)),
+                    ),
+                ),
+            );
+    }
+}
+
+#[derive(Resource, Default, Serialize, Deserialize)]
+pub struct ThemeSettings {
+    /// Folder of the selected pack, the classic look when `None`
+    pub theme: Option<String>,
+}
+
+/// Colours of the current theme
+#[derive(Resource, Clone, PartialEq)]
+pub struct Palette {
+    /// Colours of the maze for each level, starting over after the last one
+    pub mazes: Vec<MazeColors>,
+    /// Swapped with the colours of the level while the cleared board flashes
+    pub flash: MazeColors,
+    /// Body colour of each enemy, in the order they appear on the board
+    pub enemies: Vec<Color>,
+    pub frightened: Color,
+    /// Swapped with the frightened colour on the odd frames of the flashing
+    /// clip, once the enemies are about to recover
+    pub frightened_flash: Color,
+}
+
+/// Tints of the maze tiles, which are drawn in white
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+pub struct MazeColors {
+    pub walls: Color,
+    pub doors: Color,
+    /// Dots and power pills
+    pub dots: Color,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self {
+            mazes: CLASSIC_WALLS
+                .iter()
+                .map(|&walls| MazeColors {
+                    walls,
+                    doors: CLASSIC_DOORS,
+                    dots: CLASSIC_DOTS,
+                })
+                .collect(),
+            flash: FLASH_COLORS,
+            enemies: ENEMY_COLORS.to_vec(),
+            frightened: FRIGHTENED_COLOR,
+            frightened_flash: FRIGHTENED_FLASH_COLOR,
+        }
+    }
+}
+
+impl Palette {
+    /// Colours of the maze on the given level, the first one being 1
+    pub fn get_maze_colors(&self, level: usize) -> MazeColors {
+        if self.mazes.is_empty() {
+            return FLASH_COLORS;
+        }
+        self.mazes[level.saturating_sub(1) % self.mazes.len()]
+    }
+
+    pub fn get_enemy_color(&self, index: usize) -> Color {
+        if self.enemies.is_empty() {
+            return Color::WHITE;
+        }
+        self.enemies[index % self.enemies.len()]
+    }
+
+    fn swap(mut self, swap: &PaletteSwap) -> Self {
+        if let Some(mazes) = &swap.mazes {
+            self.mazes.clone_from(mazes);
+        }
+        if let Some(flash) = swap.flash {
+            self.flash = flash;
+        }
+        if let Some(enemies) = &swap.enemies {
+            self.enemies.clone_from(enemies);
+        }
+        if let Some(frightened) = swap.frightened {
+            self.frightened = frightened;
+        }
+        if let Some(frightened_flash) = swap.frightened_flash {
+            self.frightened_flash = frightened_flash;
+        }
+        self
+    }
+}
+
+/// Every theme pack listed in the index
+#[derive(Asset, TypePath)]
+pub struct ThemePacks {
+    packs: Vec<ThemePack>,
+}
+
+/// Read from the `theme.ron` of a pack, everything left out keeps its
+/// classic look. Paths are relative to the folder of the pack.
+#[derive(Deserialize)]
+struct ThemePack {
+    /// Folder of the pack, saved in the settings
+    #[serde(skip)]
+    id: String,
+    /// Shown in the settings
+    name: String,
+    /// Packed with `--pack-atlas`, regions missing from the manifest are
+    /// not drawn
+    #[serde(default)]
+    atlas: Option<ThemeAtlas>,
+    #[serde(default)]
+    font: Option<String>,
+    #[serde(default)]
+    palette: PaletteSwap,
+}
+
+#[derive(Deserialize)]
+struct ThemeAtlas {
+    image: String,
+    manifest: String,
+}
+
+/// Colours replacing the classic ones
+#[derive(Deserialize, Default)]
+struct PaletteSwap {
+    #[serde(default)]
+    mazes: Option<Vec<MazeColors>>,
+    #[serde(default)]
+    flash: Option<MazeColors>,
+    #[serde(default)]
+    enemies: Option<Vec<Color>>,
+    #[serde(default)]
+    frightened: Option<Color>,
+    #[serde(default)]
+    frightened_flash: Option<Color>,
+}
+
+#[derive(Resource, Default)]
+struct ThemeLoader {
+    packs: Handle<ThemePacks>,
+}
+
+/// Theme packs as shown in the settings
+#[derive(SystemParam)]
+pub struct Themes<'w> {
+    settings: ResMut<'w, ThemeSettings>,
+    loader: Res<'w, ThemeLoader>,
+    packs: Res<'w, Assets<ThemePacks>>,
+}
+
+impl Themes<'_> {
+    fn get_packs(&self) -> &[ThemePack] {
+        self.packs
+            .get(&self.loader.packs)
+            .map_or(&[], |packs| packs.packs.as_slice())
+    }
+
+    /// Name of the selected theme
+    pub fn get_name(&self) -> &str {
+        let selected = self.settings.theme.as_ref();
+        self.get_packs()
+            .iter()
+            .find(|pack| Some(&pack.id) == selected)
+            .map_or(DEFAULT_THEME_NAME, |pack| pack.name.as_str())
+    }
+
+    /// Selects the next pack, going back to the classic look after the last
+    pub fn select_next(&mut self) {
+        let packs = self.get_packs();
+        let current = packs
+            .iter()
+            .position(|pack| Some(&pack.id) == self.settings.theme.as_ref());
+        let next = current.map_or_else(|| packs.first(), |index| packs.get(index + 1));
+        self.settings.theme = next.map(|pack| pack.id.clone());
+    }
+}
+
+struct ThemePacksLoader;
+
+#[derive(Debug)]
+pub enum ThemePacksLoaderError {
+    Io(std::io::Error),
+    Read(ReadAssetBytesError),
+    Ron(ron::error::SpannedError),
+}
+
+impl std::fmt::Display for ThemePacksLoaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "Unable to read the theme index: {err}"),
+            Self::Read(err) => write!(f, "Unable to read a theme pack: {err}"),
+            Self::Ron(err) => write!(f, "Invalid theme pack: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ThemePacksLoaderError {}
+
+impl AssetLoader for ThemePacksLoader {
+    type Asset = ThemePacks;
+    type Settings = ();
+    type Error = ThemePacksLoaderError;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<ThemePacks, ThemePacksLoaderError>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader
+                .read_to_end(&mut bytes)
+                .await
+                .map_err(ThemePacksLoaderError::Io)?;
+            let folders: Vec<String> =
+                ron::de::from_bytes(&bytes).map_err(ThemePacksLoaderError::Ron)?;
+            let root = load_context
+                .path()
+                .parent()
+                .map(|path| path.to_string_lossy().into_owned())
+                .unwrap_or_default();
+
+            let mut packs = Vec::new();
+            for id in folders {
+                let folder = format!("{root}/{id}");
+                let bytes = load_context
+                    .read_asset_bytes(format!("{folder}/theme.ron"))
+                    .await
+                    .map_err(ThemePacksLoaderError::Read)?;
+                let mut pack: ThemePack =
+                    ron::de::from_bytes(&bytes).map_err(ThemePacksLoaderError::Ron)?;
+                if let Some(atlas) = &mut pack.atlas {
+                    atlas.image = format!("{folder}/{}", atlas.image);
+                    atlas.manifest = format!("{folder}/{}", atlas.manifest);
+                }
+                if let Some(font) = &mut pack.font {
+                    *font = format!("{folder}/{font}");
+                }
+                pack.id = id;
+                packs.push(pack);
+            }
+            Ok(ThemePacks { packs })
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["themes.ron"]
+    }
+}
+
+fn load_theme_packs(
+    mut theme_loader: ResMut<ThemeLoader>,
+    mut loading_queue: ResMut<LoadingQueue>,
+    asset_server: Res<AssetServer>,
+) {
+    theme_loader.packs = asset_server.load(THEME_INDEX);
+    loading_queue.add(theme_loader.packs.clone());
+}
+
+/// Loads what the selected pack replaces, the rest of the game picks up the
+/// new assets as they change
+fn apply_theme(
+    settings: Res<ThemeSettings>,
+    theme_loader: Res<ThemeLoader>,
+    packs: Res<Assets<ThemePacks>>,
+    asset_server: Res<AssetServer>,
+    mut game_assets: ResMut<GameAssetsLoader>,
+    mut font: ResMut<UiFont>,
+    mut palette: ResMut<Palette>,
+) {
+    // Until the index is loaded the classic look is kept
+    let Some(packs) = packs.get(&theme_loader.packs) else {
+        return;
+    };
+    let pack = packs
+        .packs
+        .iter()
+        .find(|pack| Some(&pack.id) == settings.theme.as_ref());
+
+    let (atlas, manifest) = pack
+        .and_then(|pack| pack.atlas.as_ref())
+        .map_or((ATLAS, ATLAS_MANIFEST), |atlas| {
+            (atlas.image.as_str(), atlas.manifest.as_str())
+        });
+    game_assets.set_if_neq(GameAssetsLoader::new(
+        asset_server.load(atlas.to_owned()),
+        asset_server.load(manifest.to_owned()),
+    ));
+
+    let default = pack
+        .and_then(|pack| pack.font.as_deref())
+        .unwrap_or(UI_FONT);
+    font.set_if_neq(UiFont {
+        default: asset_server.load(default.to_owned()),
+    });
+
+    let next = pack.map_or_else(Palette::default, |pack| {
+        Palette::default().swap(&pack.palette)
+    });
+    palette.set_if_neq(next);
+}
+
+fn save_theme_settings(settings: Res<ThemeSettings>) {
+    storage::save(THEME_STORAGE, &*settings);
+}
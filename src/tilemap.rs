@@ -0,0 +1,172 @@
+use crate::{movement::Position, theme::MazeColors, CELL_SIZE, TILE_CHUNK_SIZE};
+use bevy::{
+    prelude::*,
+    render::{
+        mesh::{Indices, PrimitiveTopology},
+        render_asset::RenderAssetUsages,
+    },
+    sprite::{MaterialMesh2dBundle, Mesh2dHandle},
+};
+
+/// Tiles of the maze atlas, laid out from left to right in a single row. They
+/// are drawn in white and tinted with the colours of the level.
+#[derive(Clone, Copy)]
+pub enum Tile {
+    WallVertical,
+    WallHorizontal,
+    WallTopLeft,
+    WallTopRight,
+    WallBottomLeft,
+    WallBottomRight,
+    Door,
+    Dot,
+    PowerPill,
+}
+
+impl Tile {
+    const COUNT: usize = 9;
+
+    /// Horizontal texture coordinates of the tile, within the area of the
+    /// atlas holding the maze
+    fn get_uv_range(self, uv: Rect) -> (f32, f32) {
+        let index = self as usize as f32;
+        let width = uv.width() / Self::COUNT as f32;
+        let left = index.mul_add(width, uv.min.x);
+        (left, left + width)
+    }
+
+    const fn get_color(self, colors: &MazeColors) -> Color {
+        match self {
+            Self::WallVertical
+            | Self::WallHorizontal
+            | Self::WallTopLeft
+            | Self::WallTopRight
+            | Self::WallBottomLeft
+            | Self::WallBottomRight => colors.walls,
+            Self::Door => colors.doors,
+            Self::Dot | Self::PowerPill => colors.dots,
+        }
+    }
+}
+
+/// Square of cells drawn with a single mesh
+#[derive(Component)]
+pub struct TileChunk {
+    /// Cell of the top left corner
+    origin: Position,
+    /// Area of the texture holding the tiles, from 0 to 1
+    uv: Rect,
+}
+
+impl TileChunk {
+    /// Whether the cell is drawn by this chunk
+    pub fn contains(&self, position: &Position) -> bool {
+        let size = TILE_CHUNK_SIZE as f32;
+        (self.origin.x..self.origin.x + size).contains(&position.x)
+            && (self.origin.y..self.origin.y + size).contains(&position.y)
+    }
+
+    /// Mesh drawing the given tiles, the ones outside the chunk are skipped
+    pub fn build_mesh<'a>(
+        &self,
+        tiles: impl Iterator<Item = (&'a Position, Tile)>,
+        colors: &MazeColors,
+    ) -> Mesh {
+        let mut positions = Vec::new();
+        let mut uvs = Vec::new();
+        let mut vertex_colors = Vec::new();
+        let mut indices = Vec::new();
+        for (position, tile) in tiles {
+            if !self.contains(position) {
+                continue;
+            }
+            let x = (position.x - self.origin.x) * CELL_SIZE;
+            let y = (self.origin.y - position.y) * CELL_SIZE;
+            let (left, right) = tile.get_uv_range(self.uv);
+            let (top, bottom) = (self.uv.min.y, self.uv.max.y);
+            let first = positions.len() as u32;
+            positions.extend([
+                [x, y, 0.],
+                [x + CELL_SIZE, y, 0.],
+                [x + CELL_SIZE, y - CELL_SIZE, 0.],
+                [x, y - CELL_SIZE, 0.],
+            ]);
+            uvs.extend([[left, top], [right, top], [right, bottom], [left, bottom]]);
+            vertex_colors.extend([tile.get_color(colors).as_linear_rgba_f32(); 4]);
+            indices.extend([first, first + 3, first + 2, first, first + 2, first + 1]);
+        }
+
+        Mesh::new(
+            PrimitiveTopology::TriangleList,
+            RenderAssetUsages::default(),
+        )
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, vertex_colors)
+        .with_inserted_indices(Indices::U32(indices))
+    }
+}
+
+/// Covers a board of the given size with chunks drawing the given tiles,
+/// at the given depth. Chunks without tiles are hidden until filled.
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_chunks<C: Component + Clone>(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    material: &Handle<ColorMaterial>,
+    uv: Rect,
+    (rows, columns): (usize, usize),
+    z: f32,
+    tiles: &[(Position, Tile)],
+    colors: &MazeColors,
+    marker: C,
+) {
+    for row in (0..rows).step_by(TILE_CHUNK_SIZE) {
+        for column in (0..columns).step_by(TILE_CHUNK_SIZE) {
+            let chunk = TileChunk {
+                origin: Position::new(column as f32, row as f32),
+                uv,
+            };
+            let mesh = chunk.build_mesh(
+                tiles.iter().map(|(position, tile)| (position, *tile)),
+                colors,
+            );
+            let visibility = if mesh.count_vertices() == 0 {
+                Visibility::Hidden
+            } else {
+                Visibility::Inherited
+            };
+            let transform =
+                Transform::from_xyz(chunk.origin.x * CELL_SIZE, -chunk.origin.y * CELL_SIZE, z);
+            commands.spawn((
+                MaterialMesh2dBundle {
+                    mesh: Mesh2dHandle(meshes.add(mesh)),
+                    material: material.clone(),
+                    transform,
+                    visibility,
+                    ..Default::default()
+                },
+                chunk,
+                marker.clone(),
+            ));
+        }
+    }
+}
+
+/// Replaces the tiles of a chunk, hiding it when none are left
+pub fn fill_chunk<'a>(
+    chunk: &TileChunk,
+    mesh: &Mesh2dHandle,
+    mut visibility: Mut<Visibility>,
+    meshes: &mut Assets<Mesh>,
+    tiles: impl Iterator<Item = (&'a Position, Tile)>,
+    colors: &MazeColors,
+) {
+    let built = chunk.build_mesh(tiles, colors);
+    if built.count_vertices() == 0 {
+        visibility.set_if_neq(Visibility::Hidden);
+        return;
+    }
+    meshes.insert(mesh.0.id(), built);
+    visibility.set_if_neq(Visibility::Inherited);
+}
@@ -0,0 +1,228 @@
+use crate::{
+    input::{InputDirectionEvent, MenuInputEvent},
+    movement::Directions,
+    state::{GameEvent, GameGlobals, GameState},
+    storage,
+};
+use bevy::{prelude::*, utils::HashMap, window::PrimaryWindow};
+use serde::{Deserialize, Serialize};
+
+const TOUCH_STORAGE: &str = "touch";
+/// Swipe length needed to change direction, as a fraction of the smallest
+/// side of the canvas so it feels the same at any zoom level
+const SWIPE_THRESHOLD: f32 = 0.06;
+const DPAD_BUTTON_SIZE: f32 = 48.;
+const DPAD_COLOR: Color = Color::rgba(1., 1., 1., 0.15);
+const DPAD_PRESSED_COLOR: Color = Color::rgba(1., 1., 1., 0.4);
+
+pub struct TouchPlugin;
+
+impl Plugin for TouchPlugin {
+    fn build(&self, app: &mut App) {
+        let settings = storage::load(TOUCH_STORAGE).unwrap_or_default();
+        app.insert_resource::<TouchSettings>(settings).add_systems(
+            Update,
+            (
+                read_touches,
+                read_dpad,
+                spawn_dpad.run_if(resource_changed::<TouchSettings>),
+                save_touch_settings.run_if(
+                    resource_changed::<TouchSettings>
+                        .and_then(not(resource_added::<TouchSettings>)),
+                ),
+            ),
+        );
+    }
+}
+
+#[derive(Resource, Default, Serialize, Deserialize)]
+pub struct TouchSettings {
+    /// Shows a directional pad on top of the board
+    pub dpad: bool,
+}
+
+#[derive(Component)]
+struct Dpad;
+
+#[derive(Component)]
+struct DpadButton(Directions);
+
+/// Progress of a finger on the screen
+struct TouchTrack {
+    /// Where the swipe being measured started
+    anchor: Vec2,
+    swiped: bool,
+    /// Touches that start on the directional pad are not gestures
+    ignored: bool,
+}
+
+#[allow(clippy::too_many_arguments)]
+fn read_touches(
+    touches: Res<Touches>,
+    window: Query<&Window, With<PrimaryWindow>>,
+    dpad_buttons: Query<&Interaction, With<DpadButton>>,
+    state: Res<State<GameState>>,
+    game_globals: Res<GameGlobals>,
+    mut tracks: Local<HashMap<u64, TouchTrack>>,
+    mut direction_event_writer: EventWriter<InputDirectionEvent>,
+    mut menu_event_writer: EventWriter<MenuInputEvent>,
+    mut game_event_writer: EventWriter<GameEvent>,
+) {
+    let Ok(window) = window.get_single() else {
+        return;
+    };
+    let threshold = window.width().min(window.height()) * SWIPE_THRESHOLD;
+    let on_dpad = dpad_buttons
+        .iter()
+        .any(|interaction| *interaction == Interaction::Pressed);
+
+    for touch in touches.iter() {
+        let track = tracks.entry(touch.id()).or_insert_with(|| TouchTrack {
+            anchor: touch.start_position(),
+            swiped: false,
+            ignored: on_dpad,
+        });
+        if track.ignored {
+            continue;
+        }
+
+        let delta = touch.position() - track.anchor;
+        if delta.length() < threshold {
+            continue;
+        }
+        // Screen coordinates grow downwards
+        let direction = if delta.x.abs() > delta.y.abs() {
+            if delta.x > 0. {
+                Directions::Right
+            } else {
+                Directions::Left
+            }
+        } else if delta.y > 0. {
+            Directions::Down
+        } else {
+            Directions::Up
+        };
+        track.anchor = touch.position();
+        track.swiped = true;
+
+        if game_globals.attract_mode {
+            game_event_writer.send(GameEvent::ExitToTitle);
+            continue;
+        }
+        direction_event_writer.send(InputDirectionEvent {
+            player: 0,
+            direction,
+        });
+        match direction {
+            Directions::Up => {
+                menu_event_writer.send(MenuInputEvent::Up);
+            }
+            Directions::Down => {
+                menu_event_writer.send(MenuInputEvent::Down);
+            }
+            Directions::Left | Directions::Right => (),
+        }
+    }
+
+    // The system took the touch away, it is neither a tap nor a swipe
+    for touch in touches.iter_just_canceled() {
+        tracks.remove(&touch.id());
+    }
+    for touch in touches.iter_just_released() {
+        // Touches pressed and released within the same frame were never
+        // tracked
+        let track = tracks.remove(&touch.id()).unwrap_or_else(|| TouchTrack {
+            anchor: touch.start_position(),
+            swiped: touch.distance().length() >= threshold,
+            ignored: on_dpad,
+        });
+        if track.ignored || track.swiped {
+            continue;
+        }
+        // A tap pauses the game and confirms anywhere else
+        if game_globals.attract_mode {
+            game_event_writer.send(GameEvent::ExitToTitle);
+        } else if *state.get() == GameState::Playing {
+            game_event_writer.send(GameEvent::TogglePause);
+        } else {
+            menu_event_writer.send(MenuInputEvent::Confirm);
+        }
+    }
+}
+
+/// Buttons keep sending their direction for as long as they are held, like
+/// the keys do
+fn read_dpad(
+    mut query: Query<(Ref<Interaction>, &DpadButton, &mut BackgroundColor)>,
+    mut direction_event_writer: EventWriter<InputDirectionEvent>,
+) {
+    for (interaction, button, mut background) in &mut query {
+        let pressed = *interaction == Interaction::Pressed;
+        if pressed {
+            direction_event_writer.send(InputDirectionEvent {
+                player: 0,
+                direction: button.0,
+            });
+        }
+        if interaction.is_changed() {
+            *background = if pressed {
+                DPAD_PRESSED_COLOR
+            } else {
+                DPAD_COLOR
+            }
+            .into();
+        }
+    }
+}
+
+fn spawn_dpad(
+    mut commands: Commands,
+    settings: Res<TouchSettings>,
+    query: Query<Entity, With<Dpad>>,
+) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    if !settings.dpad {
+        return;
+    }
+
+    let container = NodeBundle {
+        style: Style {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(DPAD_BUTTON_SIZE / 2.),
+            right: Val::Px(DPAD_BUTTON_SIZE / 2.),
+            display: Display::Grid,
+            grid_template_columns: RepeatedGridTrack::px(3, DPAD_BUTTON_SIZE),
+            grid_template_rows: RepeatedGridTrack::px(3, DPAD_BUTTON_SIZE),
+            ..Default::default()
+        },
+        z_index: ZIndex::Global(5),
+        ..Default::default()
+    };
+    let button = |direction, column: i16, row: i16| {
+        (
+            ButtonBundle {
+                style: Style {
+                    grid_column: GridPlacement::start(column),
+                    grid_row: GridPlacement::start(row),
+                    ..Default::default()
+                },
+                background_color: DPAD_COLOR.into(),
+                ..Default::default()
+            },
+            DpadButton(direction),
+        )
+    };
+
+    commands.spawn((container, Dpad)).with_children(|builder| {
+        builder.spawn(button(Directions::Up, 2, 1));
+        builder.spawn(button(Directions::Left, 1, 2));
+        builder.spawn(button(Directions::Right, 3, 2));
+        builder.spawn(button(Directions::Down, 2, 3));
+    });
+}
+
+fn save_touch_settings(settings: Res<TouchSettings>) {
+    storage::save(TOUCH_STORAGE, &*settings);
+}
@@ -1,18 +1,262 @@
-use crate::{game_assets::UiFont, state::GameGlobals, FONT_SIZE, UI_HEIGHT, WINDOW_HEIGHT};
+use crate::{
+    audio::AudioSettings,
+    effects::EffectsSettings,
+    game_assets::{LoadingQueue, UiFont},
+    input::{Action, InputBinding, Inputs, KeyMap, MenuInputEvent},
+    state::{GameEvent, GameGlobals, GameMode, GameState},
+    theme::Themes,
+    touch::TouchSettings,
+    FONT_SIZE, MAX_PLAYERS, UI_HEIGHT, VOLUME_STEP, WINDOW_HEIGHT,
+};
 use bevy::{prelude::*, window::WindowResized};
 
+const EXTRA_LIFE_FLASH_TIME: f32 = 1.5;
+const EXTRA_LIFE_FLASH_COLOR: Color = Color::YELLOW;
+const TITLE_FONT_SIZE: f32 = 48.;
+const MENU_SELECTED_COLOR: Color = Color::YELLOW;
+const OVERLAY_COLOR: Color = Color::rgba(0., 0., 0., 0.8);
+const LOADING_BAR_WIDTH: f32 = 240.;
+const LOADING_BAR_COLOR: Color = Color::YELLOW;
+
 pub struct GameUiPlugin;
 
 impl Plugin for GameUiPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
         app.add_systems(Startup, load_ui);
         app.add_systems(Update, update_score);
+        app.add_systems(Update, update_lives);
+        app.add_systems(Update, flash_lives);
         app.add_systems(Update, resize_ui.run_if(on_event::<WindowResized>()));
+        app.add_systems(
+            Update,
+            update_fonts.run_if(resource_changed::<UiFont>.and_then(not(resource_added::<UiFont>))),
+        );
+
+        app.init_resource::<Menu>()
+            .add_systems(OnEnter(GameState::Loading), spawn_loading)
+            .add_systems(
+                Update,
+                update_loading_bar.run_if(in_state(GameState::Loading)),
+            )
+            .add_systems(OnEnter(GameState::Title), spawn_title)
+            .add_systems(OnEnter(GameState::Menu), open_menu(MenuPage::Main))
+            .add_systems(OnEnter(GameState::Ready), spawn_ready)
+            .add_systems(OnEnter(GameState::Playing), spawn_demo)
+            .add_systems(OnEnter(GameState::Paused), open_menu(MenuPage::Pause))
+            .add_systems(OnEnter(GameState::GameOver), spawn_game_over)
+            .add_systems(OnExit(GameState::Menu), close_menu)
+            .add_systems(OnExit(GameState::Paused), close_menu)
+            .add_systems(
+                Update,
+                (
+                    title_input.run_if(in_state(GameState::Title)),
+                    menu_input
+                        .run_if(in_state(GameState::Menu).or_else(in_state(GameState::Paused))),
+                    draw_menu.run_if(resource_changed::<Menu>.or_else(resource_changed::<KeyMap>)),
+                )
+                    .chain(),
+            );
+        for state in [
+            GameState::Loading,
+            GameState::Title,
+            GameState::Menu,
+            GameState::Ready,
+            GameState::Playing,
+            GameState::Paused,
+            GameState::GameOver,
+        ] {
+            app.add_systems(OnExit(state), despawn_overlays);
+        }
+    }
+}
+
+/// Root of every screen drawn on top of the board, removed when the game
+/// state changes
+#[derive(Component)]
+struct Overlay;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum MenuPage {
+    Main,
+    Pause,
+    Settings,
+    Controls,
+}
+
+impl MenuPage {
+    const fn get_title(self) -> &'static str {
+        match self {
+            Self::Main => "MENU",
+            Self::Pause => "PAUSED",
+            Self::Settings => "SETTINGS",
+            Self::Controls => "CONTROLS",
+        }
+    }
+
+    fn get_items(self, menu: &Menu, settings: &Settings) -> Vec<(String, MenuAction)> {
+        let on_off = |value: bool| if value { "ON" } else { "OFF" };
+        match self {
+            Self::Main => vec![
+                (
+                    "1 PLAYER".into(),
+                    MenuAction::StartGame(1, GameMode::Alternating),
+                ),
+                (
+                    "2 PLAYERS".into(),
+                    MenuAction::StartGame(MAX_PLAYERS, GameMode::Alternating),
+                ),
+                (
+                    "2 PLAYERS CO-OP".into(),
+                    MenuAction::StartGame(MAX_PLAYERS, GameMode::Coop),
+                ),
+                (
+                    "2 PLAYERS VERSUS".into(),
+                    MenuAction::StartGame(MAX_PLAYERS, GameMode::Versus),
+                ),
+                ("SETTINGS".into(), MenuAction::Settings),
+                ("BACK".into(), MenuAction::ExitToTitle),
+            ],
+            Self::Pause => vec![
+                ("RESUME".into(), MenuAction::Resume),
+                ("RESTART LEVEL".into(), MenuAction::RestartLevel),
+                (
+                    "RESTART GAME".into(),
+                    MenuAction::StartGame(
+                        settings.game_globals.players.len(),
+                        settings.game_globals.mode,
+                    ),
+                ),
+                ("SETTINGS".into(), MenuAction::Settings),
+                ("QUIT TO TITLE".into(), MenuAction::ExitToTitle),
+            ],
+            Self::Settings => vec![
+                (
+                    format!("GRID {}", on_off(settings.game_globals.show_grid)),
+                    MenuAction::ToggleGrid,
+                ),
+                (
+                    format!("TOUCH PAD {}", on_off(settings.touch.dpad)),
+                    MenuAction::ToggleTouchPad,
+                ),
+                (
+                    format!("SOUND {}", on_off(!settings.audio.muted)),
+                    MenuAction::ToggleSound,
+                ),
+                (
+                    format!("VOLUME {}%", (settings.audio.volume * 100.).round()),
+                    MenuAction::ChangeVolume,
+                ),
+                (
+                    format!("THEME {}", settings.themes.get_name()),
+                    MenuAction::ChangeTheme,
+                ),
+                (
+                    format!("REDUCED MOTION {}", on_off(settings.effects.reduced_motion)),
+                    MenuAction::ToggleReducedMotion,
+                ),
+                ("CONTROLS".into(), MenuAction::Controls),
+                ("BACK".into(), MenuAction::Back),
+            ],
+            Self::Controls => {
+                let mut items: Vec<_> = Action::iterator()
+                    .map(|&action| {
+                        let bindings = if menu.capturing == Some(action) {
+                            "PRESS A KEY, BACKSPACE CLEARS".to_string()
+                        } else {
+                            settings
+                                .key_map
+                                .get_bindings(action)
+                                .iter()
+                                .map(|binding| binding.get_name())
+                                .collect::<Vec<_>>()
+                                .join(" ")
+                        };
+                        (
+                            format!("{} {bindings}", action.get_name()),
+                            MenuAction::Rebind(action),
+                        )
+                    })
+                    .collect();
+                items.push(("RESET DEFAULTS".into(), MenuAction::ResetControls));
+                items.push(("BACK".into(), MenuAction::Back));
+                items
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum MenuAction {
+    /// Starts a game for the given number of players
+    StartGame(usize, GameMode),
+    Resume,
+    RestartLevel,
+    Settings,
+    ToggleGrid,
+    ToggleTouchPad,
+    ToggleSound,
+    /// Raises the volume, going back to the lowest one after the loudest
+    ChangeVolume,
+    /// Switches to the next theme pack
+    ChangeTheme,
+    ToggleReducedMotion,
+    Controls,
+    Rebind(Action),
+    ResetControls,
+    Back,
+    ExitToTitle,
+}
+
+/// Stack of the open menu pages, the last one is the one on screen
+#[derive(Resource, Default)]
+struct Menu {
+    pages: Vec<MenuPage>,
+    selection: usize,
+    /// Action waiting for the next key press to be bound to it
+    capturing: Option<Action>,
+}
+
+impl Menu {
+    fn open(&mut self, page: MenuPage) {
+        self.pages.push(page);
+        self.selection = 0;
+        self.capturing = None;
     }
+
+    /// Goes back to the previous page, returns false when there is none
+    fn back(&mut self) -> bool {
+        self.selection = 0;
+        self.pages.pop();
+        !self.pages.is_empty()
+    }
+}
+
+#[derive(Component)]
+struct MenuRoot;
+
+/// Filled as the assets load
+#[derive(Component)]
+struct LoadingBar;
+
+/// Everything the settings pages can change
+#[derive(bevy::ecs::system::SystemParam)]
+struct Settings<'w> {
+    game_globals: ResMut<'w, GameGlobals>,
+    key_map: ResMut<'w, KeyMap>,
+    touch: ResMut<'w, TouchSettings>,
+    audio: ResMut<'w, AudioSettings>,
+    themes: Themes<'w>,
+    effects: ResMut<'w, EffectsSettings>,
 }
 
+/// Score of one of the players
 #[derive(Component)]
-struct ScoreText;
+struct ScoreText(usize);
+
+#[derive(Component, Default)]
+struct LivesText {
+    flash: Option<Timer>,
+}
 
 fn load_ui(mut commands: Commands, font: Res<UiFont>) {
     let ui_container = NodeBundle {
@@ -54,8 +298,43 @@ fn load_ui(mut commands: Commands, font: Res<UiFont>) {
         },
     );
 
+    // The second player score only shows up in two player games
+    let second_score_text = TextBundle::from_section(
+        String::new(),
+        TextStyle {
+            font: font.default.clone(),
+            font_size: FONT_SIZE,
+            color: Color::WHITE,
+        },
+    )
+    .with_style(Style {
+        position_type: PositionType::Absolute,
+        right: Val::Px(FONT_SIZE / 2.),
+        top: Val::Px(0.),
+        ..Default::default()
+    });
+
+    let lives_text = TextBundle::from_section(
+        String::new(),
+        TextStyle {
+            font: font.default.clone(),
+            font_size: FONT_SIZE,
+            color: Color::WHITE,
+        },
+    )
+    .with_style(Style {
+        position_type: PositionType::Absolute,
+        right: Val::Px(FONT_SIZE / 2.),
+        bottom: Val::Px(0.),
+        ..Default::default()
+    });
+
     commands
         .spawn(ui_container)
+        .with_children(|builder| {
+            builder.spawn((lives_text, LivesText::default()));
+            builder.spawn((second_score_text, ScoreText(1)));
+        })
         .with_children(|builder| {
             builder.spawn(row.clone()).with_children(|builder| {
                 builder.spawn(hiscore_text);
@@ -63,17 +342,363 @@ fn load_ui(mut commands: Commands, font: Res<UiFont>) {
         })
         .with_children(|builder| {
             builder.spawn(row).with_children(|builder| {
-                builder.spawn((score_text, ScoreText));
+                builder.spawn((score_text, ScoreText(0)));
             });
         });
 }
 
-fn update_score(mut query: Query<&mut Text, With<ScoreText>>, game_state: Res<GameGlobals>) {
+fn update_score(mut query: Query<(&mut Text, &ScoreText)>, game_state: Res<GameGlobals>) {
+    for (mut text, ScoreText(player)) in &mut query {
+        text.sections[0].value = match (*player, game_state.players.get(*player)) {
+            (0, Some(progress)) => progress.score.to_string(),
+            (_, Some(progress)) => format!("{}UP {}", player + 1, progress.score),
+            (_, None) => String::new(),
+        };
+    }
+}
+
+fn update_lives(mut query: Query<&mut Text, With<LivesText>>, game_state: Res<GameGlobals>) {
     let mut text = query.single_mut();
-    text.sections[0].value = game_state.score.to_string();
+    // Players sharing the board show their lives side by side
+    let lives = match game_state.mode {
+        GameMode::Alternating | GameMode::Versus => {
+            game_state.get_player().lives.max(0).to_string()
+        }
+        GameMode::Coop => game_state
+            .players
+            .iter()
+            .map(|progress| progress.lives.max(0).to_string())
+            .collect::<Vec<_>>()
+            .join(" "),
+    };
+    text.sections[0].value = format!("LIVES {lives}");
+}
+
+/// Blinks the lives counter for a moment when an extra life is awarded
+fn flash_lives(
+    mut query: Query<(&mut Text, &mut LivesText)>,
+    mut event_reader: EventReader<GameEvent>,
+    time: Res<Time<Real>>,
+) {
+    let (mut text, mut lives_text) = query.single_mut();
+    if event_reader
+        .read()
+        .any(|event| matches!(event, GameEvent::ExtraLife { .. }))
+    {
+        lives_text.flash = Some(Timer::from_seconds(EXTRA_LIFE_FLASH_TIME, TimerMode::Once));
+    }
+
+    let Some(timer) = lives_text.flash.as_mut() else {
+        return;
+    };
+    timer.tick(time.delta());
+    let color = if timer.finished() {
+        lives_text.flash = None;
+        Color::WHITE
+    } else if ((timer.elapsed_secs() * 8.) as usize).is_multiple_of(2) {
+        EXTRA_LIFE_FLASH_COLOR
+    } else {
+        Color::WHITE
+    };
+    text.sections[0].style.color = color;
+}
+
+/// Writes every text with the font of the new theme
+fn update_fonts(mut query: Query<&mut Text>, font: Res<UiFont>) {
+    for mut text in &mut query {
+        for section in &mut text.sections {
+            if section.style.font != font.default {
+                section.style.font = font.default.clone();
+            }
+        }
+    }
 }
 
 fn resize_ui(mut ui_scale: ResMut<UiScale>, mut events: EventReader<WindowResized>) {
     let event = events.read().next().unwrap();
     ui_scale.0 = event.height / (WINDOW_HEIGHT);
 }
+
+fn spawn_overlay<'a>(
+    commands: &'a mut Commands,
+    background: Color,
+) -> bevy::ecs::system::EntityCommands<'a> {
+    commands.spawn((
+        NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                width: Val::Percent(100.),
+                height: Val::Percent(100.),
+                flex_direction: FlexDirection::Column,
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                row_gap: Val::Px(FONT_SIZE / 2.),
+                ..Default::default()
+            },
+            background_color: BackgroundColor(background),
+            z_index: ZIndex::Global(10),
+            ..Default::default()
+        },
+        Overlay,
+    ))
+}
+
+fn text(font: &UiFont, value: &str, font_size: f32, color: Color) -> TextBundle {
+    TextBundle::from_section(
+        value,
+        TextStyle {
+            font: font.default.clone(),
+            font_size,
+            color,
+        },
+    )
+}
+
+fn despawn_overlays(mut commands: Commands, query: Query<Entity, With<Overlay>>) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+fn spawn_loading(mut commands: Commands, font: Res<UiFont>) {
+    spawn_overlay(&mut commands, Color::BLACK).with_children(|builder| {
+        builder.spawn(text(&font, "LOADING", FONT_SIZE, Color::WHITE));
+        builder
+            .spawn(NodeBundle {
+                style: Style {
+                    width: Val::Px(LOADING_BAR_WIDTH),
+                    height: Val::Px(FONT_SIZE / 2.),
+                    border: UiRect::all(Val::Px(2.)),
+                    ..Default::default()
+                },
+                border_color: BorderColor(Color::WHITE),
+                ..Default::default()
+            })
+            .with_children(|builder| {
+                builder.spawn((
+                    NodeBundle {
+                        style: Style {
+                            width: Val::Percent(0.),
+                            height: Val::Percent(100.),
+                            ..Default::default()
+                        },
+                        background_color: BackgroundColor(LOADING_BAR_COLOR),
+                        ..Default::default()
+                    },
+                    LoadingBar,
+                ));
+            });
+    });
+}
+
+fn update_loading_bar(
+    mut query: Query<&mut Style, With<LoadingBar>>,
+    loading_queue: Res<LoadingQueue>,
+    asset_server: Res<AssetServer>,
+) {
+    let width = Val::Percent(loading_queue.get_progress(&asset_server) * 100.);
+    for mut style in &mut query {
+        if style.width != width {
+            style.width = width;
+        }
+    }
+}
+
+fn spawn_title(mut commands: Commands, font: Res<UiFont>) {
+    spawn_overlay(&mut commands, Color::BLACK).with_children(|builder| {
+        builder.spawn(text(&font, "CAPMAN-RS", TITLE_FONT_SIZE, Color::YELLOW));
+        builder.spawn(text(&font, "PRESS ENTER", FONT_SIZE, Color::WHITE));
+    });
+}
+
+fn spawn_ready(mut commands: Commands, font: Res<UiFont>, game_globals: Res<GameGlobals>) {
+    spawn_overlay(&mut commands, Color::NONE).with_children(|builder| {
+        // Tell who is up next when the players take turns
+        if game_globals.mode == GameMode::Alternating && game_globals.players.len() > 1 {
+            let player = format!("PLAYER {}", game_globals.current_player + 1);
+            builder.spawn(text(&font, &player, FONT_SIZE, Color::CYAN));
+        }
+        builder.spawn(text(&font, "READY!", FONT_SIZE, Color::YELLOW));
+    });
+}
+
+fn spawn_demo(mut commands: Commands, font: Res<UiFont>, game_globals: Res<GameGlobals>) {
+    if !game_globals.attract_mode {
+        return;
+    }
+    spawn_overlay(&mut commands, Color::NONE).with_children(|builder| {
+        builder.spawn(text(&font, "DEMO", FONT_SIZE, Color::RED));
+    });
+}
+
+fn spawn_game_over(mut commands: Commands, font: Res<UiFont>) {
+    spawn_overlay(&mut commands, Color::NONE).with_children(|builder| {
+        builder.spawn(text(&font, "GAME OVER", FONT_SIZE, Color::RED));
+    });
+}
+
+fn title_input(
+    mut menu_reader: EventReader<MenuInputEvent>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    if menu_reader
+        .read()
+        .any(|event| *event == MenuInputEvent::Confirm)
+    {
+        next_state.set(GameState::Menu);
+    }
+}
+
+fn open_menu(page: MenuPage) -> impl Fn(ResMut<Menu>) {
+    move |mut menu: ResMut<Menu>| {
+        menu.pages.clear();
+        menu.open(page);
+    }
+}
+
+fn close_menu(mut menu: ResMut<Menu>) {
+    menu.pages.clear();
+    menu.capturing = None;
+}
+
+fn menu_input(
+    mut menu_reader: EventReader<MenuInputEvent>,
+    mut menu: ResMut<Menu>,
+    mut game_event_writer: EventWriter<GameEvent>,
+    mut settings: Settings,
+    inputs: Inputs,
+) {
+    let Some(&page) = menu.pages.last() else {
+        return;
+    };
+
+    // While rebinding, the next key press is added to the key map instead of
+    // going to the menu. Escape cancels it and backspace clears the action.
+    if let Some(action) = menu.capturing {
+        if let Some(binding) = inputs.get_any_just_pressed() {
+            match binding {
+                InputBinding::Key(KeyCode::Escape) => (),
+                InputBinding::Key(KeyCode::Backspace) => settings.key_map.clear(action),
+                _ => settings.key_map.bind(action, binding),
+            }
+            menu.capturing = None;
+            menu_reader.clear();
+        }
+        return;
+    }
+
+    let items = page.get_items(&menu, &settings);
+    for event in menu_reader.read() {
+        let action = match event {
+            MenuInputEvent::Up => {
+                menu.selection = (menu.selection + items.len() - 1) % items.len();
+                continue;
+            }
+            MenuInputEvent::Down => {
+                menu.selection = (menu.selection + 1) % items.len();
+                continue;
+            }
+            MenuInputEvent::Back => MenuAction::Back,
+            MenuInputEvent::Confirm => items[menu.selection].1,
+        };
+
+        match action {
+            MenuAction::StartGame(players, mode) => {
+                game_event_writer.send(GameEvent::NewGame {
+                    attract_mode: false,
+                    players,
+                    mode,
+                });
+            }
+            MenuAction::Resume => {
+                game_event_writer.send(GameEvent::TogglePause);
+            }
+            MenuAction::RestartLevel => {
+                game_event_writer.send(GameEvent::RestartLevel);
+            }
+            MenuAction::Settings => menu.open(MenuPage::Settings),
+            MenuAction::ToggleGrid => {
+                settings.game_globals.show_grid ^= true;
+                menu.set_changed();
+            }
+            MenuAction::ToggleTouchPad => {
+                settings.touch.dpad ^= true;
+                menu.set_changed();
+            }
+            MenuAction::ToggleSound => {
+                settings.audio.muted ^= true;
+                menu.set_changed();
+            }
+            MenuAction::ChangeVolume => {
+                let volume = settings.audio.volume + VOLUME_STEP;
+                settings.audio.volume = if volume > 1. + f32::EPSILON {
+                    VOLUME_STEP
+                } else {
+                    volume.min(1.)
+                };
+                menu.set_changed();
+            }
+            MenuAction::ChangeTheme => {
+                settings.themes.select_next();
+                menu.set_changed();
+            }
+            MenuAction::ToggleReducedMotion => {
+                settings.effects.reduced_motion ^= true;
+                menu.set_changed();
+            }
+            MenuAction::Controls => menu.open(MenuPage::Controls),
+            MenuAction::Rebind(action) => menu.capturing = Some(action),
+            MenuAction::ResetControls => {
+                *settings.key_map = KeyMap::default();
+                menu.set_changed();
+            }
+            MenuAction::Back => {
+                if !menu.back() {
+                    // Backing out of the root page closes the menu
+                    game_event_writer.send(match page {
+                        MenuPage::Pause => GameEvent::TogglePause,
+                        MenuPage::Main | MenuPage::Settings | MenuPage::Controls => {
+                            GameEvent::ExitToTitle
+                        }
+                    });
+                }
+            }
+            MenuAction::ExitToTitle => {
+                game_event_writer.send(GameEvent::ExitToTitle);
+            }
+        }
+        // The page may have changed, the rest of the input belongs to the
+        // next frame
+        break;
+    }
+}
+
+/// Rebuilds the menu on screen every time the page or the selection changes
+fn draw_menu(
+    mut commands: Commands,
+    menu: Res<Menu>,
+    settings: Settings,
+    font: Res<UiFont>,
+    query: Query<Entity, With<MenuRoot>>,
+) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    let Some(&page) = menu.pages.last() else {
+        return;
+    };
+
+    spawn_overlay(&mut commands, OVERLAY_COLOR)
+        .insert(MenuRoot)
+        .with_children(|builder| {
+            builder.spawn(text(&font, page.get_title(), FONT_SIZE * 2., Color::WHITE));
+            for (index, (label, _)) in page.get_items(&menu, &settings).iter().enumerate() {
+                let color = if index == menu.selection {
+                    MENU_SELECTED_COLOR
+                } else {
+                    Color::WHITE
+                };
+                builder.spawn(text(&font, label, FONT_SIZE, color));
+            }
+        });
+}